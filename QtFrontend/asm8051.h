@@ -14,6 +14,8 @@ extern "C" {
         uint64_t reg_len;
         uint8_t* memory;
         uint64_t memory_len;
+        uint8_t* errors;
+        uint64_t errors_len;
     };
     AsmData RustAssemble(const char* , uint64_t len);
 }
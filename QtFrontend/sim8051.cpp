@@ -159,6 +159,10 @@ void Assembler::Compile() {
   if (ffi_data.compiled) {
     std::cerr << "Program compiled successfully";
   }
+  if (ffi_data.errors_len) {
+    std::cerr << std::string(reinterpret_cast<char *>(ffi_data.errors),
+                             ffi_data.errors_len);
+  }
 
   std::vector<uint8_t> regs(ffi_data.reg_arr,
                             ffi_data.reg_arr + ffi_data.reg_len);
@@ -1,5 +1,13 @@
 use std::str::FromStr;
 
+use crate::ihex;
+use crate::instruction::{Instruction, Operand};
+use crate::interrupt::Interrupts;
+use crate::port::PortPins;
+use crate::serial::Serial;
+use crate::timer::Timers;
+use crate::xdata::XdataBus;
+
 // Memory emulation of 8051 -> Partial emulation + simulation
 pub struct RegisterBank<'a> {
     pub ptr: &'a mut [u8],
@@ -17,22 +25,50 @@ impl<'a> RegisterBank<'a> {
     }
 }
 
-//  The first 128 bytes of memory are general purpose and the remaining is for internal purpose where various special purpose registers are mapped
+//  The first 128 bytes of memory are general purpose and the upper 128 bytes share their addresses with the special purpose registers
+//  Addressing mode decides which one is reached .. direct addressing of 80H - FFH reaches the SFRs, indirect addressing (@Ri, stack) the RAM
 //  This is the internal RAM memory
 pub struct InternalMemory {
     pub memory: [u8; 256], // This is RAM and address from 20H to 2F H are bit addressable and used along with SETB to address from 00H to 7FH
+    pub sfr: [u8; 128], // SFR space from 80H to FFH, addresses without a register just read back what was written
 }
 
 impl Default for InternalMemory {
     fn default() -> InternalMemory {
         InternalMemory {
             memory: [0x00; 256],
+            sfr: [0x00; 128],
         }
     }
 }
 
 impl InternalMemory {
-    pub fn get_register_bank(&mut self, n: u8) -> RegisterBank {
+    // Byte at a direct address, lower 128 bytes are RAM and the rest are SFRs
+    pub fn direct(&self, addr: u8) -> u8 {
+        if addr < 0x80 {
+            self.memory[addr as usize]
+        } else {
+            self.sfr[addr as usize - 0x80]
+        }
+    }
+
+    pub fn set_direct(&mut self, addr: u8, val: u8) {
+        if addr < 0x80 {
+            self.memory[addr as usize] = val;
+        } else {
+            self.sfr[addr as usize - 0x80] = val;
+        }
+    }
+
+    pub fn read_sfr(&self, sfr: &SFR) -> u8 {
+        self.direct(sfr_addr(sfr))
+    }
+
+    pub fn write_sfr(&mut self, sfr: &SFR, val: u8) {
+        self.set_direct(sfr_addr(sfr), val);
+    }
+
+    pub fn get_register_bank(&mut self, n: u8) -> RegisterBank<'_> {
         assert!(n < 4); // lol nice catch rustc
         let start: usize = n as usize * 8;
         RegisterBank {
@@ -40,8 +76,19 @@ impl InternalMemory {
         }
     }
 
+    // Bits 00H - 7FH live in RAM from 20H to 2FH, rest are the bits of SFRs whose address is divisible by 8
+    // Gives the byte holding the bit and the position of the bit within it
+    pub fn bit_location(pos: u8) -> (u8, u8) {
+        if pos < 0x80 {
+            (0x20 + pos / 8, pos % 8)
+        } else {
+            (pos & 0xF8, pos % 8)
+        }
+    }
+
     pub fn operate_bit_addressable_memory(&mut self, pos: u8, operator: fn(&mut Self, u8, u8)) {
-        operator(self, 0x20 + pos / 8, pos % 8);
+        let (addr, bit) = Self::bit_location(pos);
+        operator(self, addr, bit);
     }
 
     pub fn operate_bit_addressable_registers(
@@ -54,36 +101,54 @@ impl InternalMemory {
     }
 
     pub fn get_bit_status_addr_memory(&mut self, pos: u8) -> bool {
-        let loc = 0x20 + pos / 8;
-        let rem = pos % 8;
-        (self.memory[loc as usize] & (1 << rem)) > 0
+        let (addr, bit) = Self::bit_location(pos);
+        (self.direct(addr) & (1 << bit)) > 0
     }
 
     pub fn set_bit_addressable(&mut self, pos: u8, bit: u8) {
-        self.memory[pos as usize] |= 1 << bit;
+        self.set_direct(pos, self.direct(pos) | (1 << bit));
     }
 
     pub fn reset_bit_addressable(&mut self, pos: u8, bit: u8) {
-        self.memory[pos as usize] &= !(1 << bit);
+        self.set_direct(pos, self.direct(pos) & !(1 << bit));
     }
 
     pub fn complement_bit_addressable(&mut self, pos: u8, bit: u8) {
         // let pos = (0x) // Lets continue it later by thinking of what we going to do with assembler parsing and assembling
-        self.memory[pos as usize] ^= 1 << bit;
+        self.set_direct(pos, self.direct(pos) ^ (1 << bit));
     }
 }
 
 // Do pattern matching
+// Every SFR of the 8051 and 8052 other than the ports
 #[derive(Debug)]
 pub enum IRegs {
     // Non bit adderssable
     SP,
-    DPTR,
+    DPTR, // same as DPL
+    DPL,
+    DPH,
+    PCON,
+    TMOD,
+    TL0,
+    TL1,
+    TH0,
+    TH1,
+    SBUF,
+    T2MOD, // 8052 only from here
+    RCAP2L,
+    RCAP2H,
+    TL2,
+    TH2,
     // Bit addressables
+    TCON,
+    SCON,
+    IE,
+    IP,
+    T2CON,
     PSW,
     ACC,
     B,
-    IP,
 }
 
 #[derive(Debug)]
@@ -102,7 +167,7 @@ pub enum SFR {
 
 // What to do about Special Function Registers mapping? Since, its partial emulation that need to be considered too
 pub struct Sim8051 {
-    PC: u16,
+    pc: u16,
     pub internal_memory: InternalMemory,
     // Special purpose registers
     pub code_memory: [u8; 64 * 1024],
@@ -110,25 +175,92 @@ pub struct Sim8051 {
     pub accumulator: SFR,
     pub register_b: SFR,
     pub psw: SFR,
+    pub timers: Timers,
+    pub serial: Serial,
+    pub interrupts: Interrupts,
+    pub port_pins: PortPins,
+    pub xdata: XdataBus,
 }
 
 impl Default for Sim8051 {
     fn default() -> Sim8051 {
-        Sim8051 {
+        let mut sim = Sim8051 {
             internal_memory: InternalMemory::default(),
-            PC: 0x0000,
+            pc: 0x0000,
             code_memory: [0; 64 * 1024],
             data_memory: [0; 64 * 1024],
             accumulator: SFR::Reg(IRegs::ACC),
             register_b: SFR::Reg(IRegs::B),
             psw: SFR::Reg(IRegs::PSW),
+            timers: Timers::default(),
+            serial: Serial::default(),
+            interrupts: Interrupts::default(),
+            port_pins: PortPins::default(),
+            xdata: XdataBus::default(),
+        };
+        sim.reset();
+        sim
+    }
+}
+
+// What internal RAM holds after a cold power on .. hardware leaves it undefined, so programs must not rely on it
+#[derive(Debug, Clone, Copy)]
+pub enum PowerOnRam {
+    Fill(u8),
+    Random(u64), // seed, the same seed always gives the same contents
+}
+
+// Reset and power on
+impl Sim8051 {
+    // Same as pulling the RST pin high .. SFRs get their datasheet values, RAM keeps its contents
+    pub fn reset(&mut self) {
+        use IRegs::*;
+        use Ports::*;
+        self.pc = 0x0000;
+        self.internal_memory.sfr = [0x00; 128];
+        self.timers = Timers::default();
+        self.serial.reset();
+        self.interrupts = Interrupts::default();
+        self.xdata.reset();
+        let values = [
+            (SFR::Reg(SP), 0x07),
+            (SFR::Port(P0), 0xFF),
+            (SFR::Port(P1), 0xFF),
+            (SFR::Port(P2), 0xFF),
+            (SFR::Port(P3), 0xFF),
+        ];
+        // Everything else including ACC, B, PSW, DPTR, IE, IP, the timers and the serial port resets to 00H
+        for (sfr, val) in values {
+            self.internal_memory.write_sfr(&sfr, val);
         }
     }
+
+    // Cold start, RAM is filled the given way before the reset
+    pub fn power_on(&mut self, ram: PowerOnRam) {
+        match ram {
+            PowerOnRam::Fill(val) => self.internal_memory.memory = [val; 256],
+            PowerOnRam::Random(seed) => {
+                // xorshift is plenty to make uninitialized variables stand out
+                // Zero is the one state xorshift never leaves
+                let mut state = seed ^ 0x9E37_79B9_7F4A_7C15;
+                if state == 0 {
+                    state = 1;
+                }
+                for byte in self.internal_memory.memory.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    *byte = (state >> 24) as u8;
+                }
+            }
+        }
+        self.reset();
+    }
 }
 
 impl Sim8051 {
     pub fn mov(&mut self, dst: u8, src: u8) {
-        self.internal_memory.memory[dst as usize] = src;
+        self.internal_memory.set_direct(dst, src);
     }
 
     pub fn show_scratchpad_registers(&mut self) {
@@ -137,14 +269,14 @@ impl Sim8051 {
     }
 
     pub fn show_flags(&self) {
-        let pos = sfr_addr(&SFR::Reg(IRegs::PSW)) as usize;
+        let psw = self.internal_memory.read_sfr(&self.psw);
         let flags = vec!["C", "AC", "F0", "RS1", "RS0", "OV", "_", "P"];
         for i in flags {
             print!("{:<10}", i);
         }
         println!();
         for i in (0..8).rev() {
-            print!("{:10}", (self.internal_memory.memory[pos] & (1 << i)) > 0);
+            print!("{:10}", (psw & (1 << i)) > 0);
         }
         println!();
     }
@@ -155,23 +287,47 @@ impl Sim8051 {
         use SFR::*;
         println!("\nShowing SFR contents :\n");
 
-        let vec = vec![
+        let vec = [
             Reg(PSW),
             Reg(ACC),
             Reg(B),
+            Reg(SP),
+            Reg(DPL),
+            Reg(DPH),
             Port(P0),
             Port(P1),
             Port(P2),
             Port(P3),
+            Reg(IE),
+            Reg(IP),
+            Reg(TCON),
+            Reg(TMOD),
+            Reg(TL0),
+            Reg(TH0),
+            Reg(TL1),
+            Reg(TH1),
+            Reg(SCON),
+            Reg(SBUF),
+            Reg(PCON),
+            Reg(T2CON),
+            Reg(T2MOD),
+            Reg(RCAP2L),
+            Reg(RCAP2H),
+            Reg(TL2),
+            Reg(TH2),
+        ];
+        let vecname = [
+            "PSW ", "A", "B", "SP", "DPL", "DPH", "P0", "P1", "P2", "P3", "IE", "IP", "TCON",
+            "TMOD", "TL0", "TH0", "TL1", "TH1", "SCON", "SBUF", "PCON", "T2CON", "T2MOD", "RCAP2L",
+            "RCAP2H", "TL2", "TH2",
         ];
-        let vecname = vec!["PSW ", "A", "B", "P0", "P1", "P2", "P3"];
         let mapping = vec.iter().zip(vecname.iter());
 
         for (val, name) in mapping {
             println!(
                 "{:<10} -> {:#04x}",
                 name,
-                self.internal_memory.memory[sfr_addr(&val) as usize]
+                self.internal_memory.read_sfr(val)
             );
         }
     }
@@ -187,35 +343,221 @@ pub fn sfr_addr(sfr: &SFR) -> u8 {
         },
         SFR::Reg(reg) => {
             match reg {
+                IRegs::SP => 0x81,
+                IRegs::DPTR => 0x82, // Returning only the lower order byte .. guess the rest yourself
+                IRegs::DPL => 0x82,
+                IRegs::DPH => 0x83,
+                IRegs::PCON => 0x87,
+                IRegs::TCON => 0x88,
+                IRegs::TMOD => 0x89,
+                IRegs::TL0 => 0x8A,
+                IRegs::TL1 => 0x8B,
+                IRegs::TH0 => 0x8C,
+                IRegs::TH1 => 0x8D,
+                IRegs::SCON => 0x98,
+                IRegs::SBUF => 0x99,
+                IRegs::IE => 0xA8,
+                IRegs::IP => 0xB8,
+                IRegs::T2CON => 0xC8,
+                IRegs::T2MOD => 0xC9,
+                IRegs::RCAP2L => 0xCA,
+                IRegs::RCAP2H => 0xCB,
+                IRegs::TL2 => 0xCC,
+                IRegs::TH2 => 0xCD,
+                IRegs::PSW => 0xD0,
                 IRegs::ACC => 0xE0,
                 IRegs::B => 0xF0,
-                IRegs::PSW => 0xD0,
-                IRegs::IP => 0xB8,
-                IRegs::DPTR => 0x82, // Returning only the lower order byte .. guess the rest yourself
-                IRegs::SP => 0x81,
             }
         }
     }
 }
 
-// Only bit addressable register implement this traits
+// Only the SFRs at addresses divisible by 8 have bits of their own
+pub fn is_bit_addressable(sfr: &SFR) -> bool {
+    !matches!(sfr, SFR::Reg(IRegs::DPTR)) && sfr_addr(sfr) & 0x07 == 0
+}
+
+// Address in the bit space of bit n of the given byte, None when the byte has no bits of its own
+pub fn bit_address(byte: u8, bit: u8) -> Option<u8> {
+    match byte {
+        _ if bit > 7 => None,
+        0x20..=0x2F => Some((byte - 0x20) * 8 + bit),
+        0x80..=0xFF if byte & 0x07 == 0 => Some(byte + bit),
+        _ => None,
+    }
+}
+
+// SFR names as used in the source, DPTR isn't one of them as it is a register pair
 impl FromStr for SFR {
     type Err = ();
 
     fn from_str(input: &str) -> Result<SFR, Self::Err> {
-        match input {
-            "P0" => Ok(SFR::Port(Ports::P0)),
-            "P1" => Ok(SFR::Port(Ports::P1)),
-            "P2" => Ok(SFR::Port(Ports::P2)),
-            "P3" => Ok(SFR::Port(Ports::P3)),
-            "ACC" => Ok(SFR::Reg(IRegs::ACC)),
-            "B" => Ok(SFR::Reg(IRegs::B)),
-            "PSW" => Ok(SFR::Reg(IRegs::PSW)),
-            _ => Err(()), // indicates not a bit addressable register
-        }
+        use IRegs::*;
+        let reg = match input.to_ascii_uppercase().as_str() {
+            "P0" => return Ok(SFR::Port(Ports::P0)),
+            "P1" => return Ok(SFR::Port(Ports::P1)),
+            "P2" => return Ok(SFR::Port(Ports::P2)),
+            "P3" => return Ok(SFR::Port(Ports::P3)),
+            "SP" => SP,
+            "DPL" => DPL,
+            "DPH" => DPH,
+            "PCON" => PCON,
+            "TCON" => TCON,
+            "TMOD" => TMOD,
+            "TL0" => TL0,
+            "TL1" => TL1,
+            "TH0" => TH0,
+            "TH1" => TH1,
+            "SCON" => SCON,
+            "SBUF" => SBUF,
+            "IE" => IE,
+            "IP" => IP,
+            "T2CON" => T2CON,
+            "T2MOD" => T2MOD,
+            "RCAP2L" => RCAP2L,
+            "RCAP2H" => RCAP2H,
+            "TL2" => TL2,
+            "TH2" => TH2,
+            "PSW" => PSW,
+            "ACC" => ACC,
+            "B" => B,
+            _ => return Err(()), // indicates not an SFR
+        };
+        Ok(SFR::Reg(reg))
     }
 }
 
+// Bit addresses of the named SFR bits, usable wherever a bit address is
+pub mod bits {
+    // TCON
+    pub const IT0: u8 = 0x88;
+    pub const IE0: u8 = 0x89;
+    pub const IT1: u8 = 0x8A;
+    pub const IE1: u8 = 0x8B;
+    pub const TR0: u8 = 0x8C;
+    pub const TF0: u8 = 0x8D;
+    pub const TR1: u8 = 0x8E;
+    pub const TF1: u8 = 0x8F;
+    // P1 pins of timer 2 on the 8052
+    pub const T2: u8 = 0x90;
+    pub const T2EX: u8 = 0x91;
+    // SCON
+    pub const RI: u8 = 0x98;
+    pub const TI: u8 = 0x99;
+    pub const RB8: u8 = 0x9A;
+    pub const TB8: u8 = 0x9B;
+    pub const REN: u8 = 0x9C;
+    pub const SM2: u8 = 0x9D;
+    pub const SM1: u8 = 0x9E;
+    pub const SM0: u8 = 0x9F;
+    // IE
+    pub const EX0: u8 = 0xA8;
+    pub const ET0: u8 = 0xA9;
+    pub const EX1: u8 = 0xAA;
+    pub const ET1: u8 = 0xAB;
+    pub const ES: u8 = 0xAC;
+    pub const ET2: u8 = 0xAD;
+    pub const EA: u8 = 0xAF;
+    // P3 pins
+    pub const RXD: u8 = 0xB0;
+    pub const TXD: u8 = 0xB1;
+    pub const INT0: u8 = 0xB2;
+    pub const INT1: u8 = 0xB3;
+    pub const T0: u8 = 0xB4;
+    pub const T1: u8 = 0xB5;
+    pub const WR: u8 = 0xB6;
+    pub const RD: u8 = 0xB7;
+    // IP
+    pub const PX0: u8 = 0xB8;
+    pub const PT0: u8 = 0xB9;
+    pub const PX1: u8 = 0xBA;
+    pub const PT1: u8 = 0xBB;
+    pub const PS: u8 = 0xBC;
+    pub const PT2: u8 = 0xBD;
+    // T2CON
+    pub const CP_RL2: u8 = 0xC8;
+    pub const C_T2: u8 = 0xC9;
+    pub const TR2: u8 = 0xCA;
+    pub const EXEN2: u8 = 0xCB;
+    pub const TCLK: u8 = 0xCC;
+    pub const RCLK: u8 = 0xCD;
+    pub const EXF2: u8 = 0xCE;
+    pub const TF2: u8 = 0xCF;
+    // PSW
+    pub const P: u8 = 0xD0;
+    pub const F1: u8 = 0xD1;
+    pub const OV: u8 = 0xD2;
+    pub const RS0: u8 = 0xD3;
+    pub const RS1: u8 = 0xD4;
+    pub const F0: u8 = 0xD5;
+    pub const AC: u8 = 0xD6;
+    pub const CY: u8 = 0xD7;
+}
+
+const BIT_NAMES: [(&str, u8); 55] = [
+    ("IT0", bits::IT0),
+    ("IE0", bits::IE0),
+    ("IT1", bits::IT1),
+    ("IE1", bits::IE1),
+    ("TR0", bits::TR0),
+    ("TF0", bits::TF0),
+    ("TR1", bits::TR1),
+    ("TF1", bits::TF1),
+    ("T2", bits::T2),
+    ("T2EX", bits::T2EX),
+    ("RI", bits::RI),
+    ("TI", bits::TI),
+    ("RB8", bits::RB8),
+    ("TB8", bits::TB8),
+    ("REN", bits::REN),
+    ("SM2", bits::SM2),
+    ("SM1", bits::SM1),
+    ("SM0", bits::SM0),
+    ("EX0", bits::EX0),
+    ("ET0", bits::ET0),
+    ("EX1", bits::EX1),
+    ("ET1", bits::ET1),
+    ("ES", bits::ES),
+    ("ET2", bits::ET2),
+    ("EA", bits::EA),
+    ("RXD", bits::RXD),
+    ("TXD", bits::TXD),
+    ("INT0", bits::INT0),
+    ("INT1", bits::INT1),
+    ("T0", bits::T0),
+    ("T1", bits::T1),
+    ("WR", bits::WR),
+    ("RD", bits::RD),
+    ("PX0", bits::PX0),
+    ("PT0", bits::PT0),
+    ("PX1", bits::PX1),
+    ("PT1", bits::PT1),
+    ("PS", bits::PS),
+    ("PT2", bits::PT2),
+    ("CP_RL2", bits::CP_RL2),
+    ("C_T2", bits::C_T2),
+    ("TR2", bits::TR2),
+    ("EXEN2", bits::EXEN2),
+    ("TCLK", bits::TCLK),
+    ("RCLK", bits::RCLK),
+    ("EXF2", bits::EXF2),
+    ("TF2", bits::TF2),
+    ("P", bits::P),
+    ("F1", bits::F1),
+    ("OV", bits::OV),
+    ("RS0", bits::RS0),
+    ("RS1", bits::RS1),
+    ("F0", bits::F0),
+    ("AC", bits::AC),
+    ("CY", bits::CY),
+];
+
+// Bit address of a named SFR bit, in any case
+pub fn named_bit(name: &str) -> Option<u8> {
+    let name = name.to_ascii_uppercase();
+    BIT_NAMES.iter().find(|x| x.0 == name).map(|x| x.1)
+}
+
 #[derive(Debug)]
 pub enum ScratchpadRegisters {
     R0,
@@ -247,7 +589,7 @@ impl FromStr for ScratchpadRegisters {
     type Err = ();
 
     fn from_str(input: &str) -> Result<ScratchpadRegisters, Self::Err> {
-        match input {
+        match input.to_ascii_uppercase().as_str() {
             "R0" => Ok(ScratchpadRegisters::R0),
             "R1" => Ok(ScratchpadRegisters::R1),
             "R2" => Ok(ScratchpadRegisters::R2),
@@ -262,56 +604,668 @@ impl FromStr for ScratchpadRegisters {
 }
 
 impl Sim8051 {
-    pub fn get_active_register_bank(&mut self) -> RegisterBank {
-        let pswloc = sfr_addr(&SFR::Reg(IRegs::PSW)) as usize;
-        let count = (0x18 & self.internal_memory.memory[pswloc]) >> 3;
+    pub fn get_active_register_bank(&mut self) -> RegisterBank<'_> {
+        let count = (0x18 & self.internal_memory.read_sfr(&self.psw)) >> 3;
         let start: usize = count as usize * 8;
         RegisterBank {
             ptr: &mut self.internal_memory.memory[start..start + 8],
         }
     }
 
-    // TODO :: Later
+    // P always follows the parity of the accumulator, the core calls this after every instruction
     pub fn set_parity_bit(&mut self, val: u8) {
         // Don't ask what below code does :D -_-
-        let parity: u64 = (((val as u64 * 0x0101010101010101 as u64) & 0x8040201008040201 as u64)
-            % 0x1FF as u64)
-            & (1 as u64);
+        let parity: u64 =
+            (((val as u64 * 0x0101010101010101_u64) & 0x8040201008040201_u64) % 0x1FF_u64) & 1_u64;
         let even_parity = parity == 0;
-        // what's the bit count of PSW.7?
-        let addr = sfr_addr(&self.psw);
-        // retrieve its value, either set it or reset it
-        if even_parity {
-            self.internal_memory.memory[addr as usize] &= 0xFE;
-        } else {
-            self.internal_memory.memory[addr as usize] |= 0x01;
-        }
+        // P is PSW.0
+        self.write_bit(bits::P, !even_parity);
     }
 
     pub fn set_carry_bit(&mut self, set: bool) {
-        let addr = sfr_addr(&self.psw);
-        if set {
-            self.internal_memory.memory[addr as usize] |= 0x80;
-        } else {
-            self.internal_memory.memory[addr as usize] &= 0x7F;
-        }
+        self.write_bit(bits::CY, set);
     }
 
     pub fn set_auxiliary_carry_bit(&mut self, set: bool) {
-        let addr = sfr_addr(&self.psw);
+        self.write_bit(bits::AC, set);
+    }
+
+    pub fn set_overflow_bit(&mut self, set: bool) {
+        self.write_bit(bits::OV, set);
+    }
+}
+
+// Fetch, decode and execute cycle of the 8051 core
+// Instructions are decoded into the same Instruction the assembler encodes, so both agree on every opcode
+impl Sim8051 {
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn read_direct(&self, addr: u8) -> u8 {
+        self.internal_memory.direct(addr)
+    }
+
+    // SBUF keeps what was received, a write to it goes to the transmitter instead
+    pub fn write_direct(&mut self, addr: u8, val: u8) {
+        if addr == sfr_addr(&SFR::Reg(IRegs::SBUF)) {
+            self.serial_transmit(val);
+            return;
+        }
+        if addr == sfr_addr(&SFR::Reg(IRegs::IE)) || addr == sfr_addr(&SFR::Reg(IRegs::IP)) {
+            self.interrupts.hold();
+        }
+        self.internal_memory.set_direct(addr, val);
+    }
+
+    // Indirect addressing never reaches the SFRs, 80H - FFH is the upper 128 bytes of RAM
+    pub fn read_indirect(&self, addr: u8) -> u8 {
+        self.internal_memory.memory[addr as usize]
+    }
+
+    pub fn write_indirect(&mut self, addr: u8, val: u8) {
+        self.internal_memory.memory[addr as usize] = val;
+    }
+
+    pub fn read_bit(&self, bit: u8) -> bool {
+        let (addr, pos) = InternalMemory::bit_location(bit);
+        (self.read_direct(addr) & (1 << pos)) > 0
+    }
+
+    pub fn write_bit(&mut self, bit: u8, set: bool) {
+        let (addr, pos) = InternalMemory::bit_location(bit);
+        let val = self.read_direct(addr);
         if set {
-            self.internal_memory.memory[addr as usize] |= 0x40;
+            self.write_direct(addr, val | (1 << pos));
         } else {
-            self.internal_memory.memory[addr as usize] &= 0xBF;
+            self.write_direct(addr, val & !(1 << pos));
         }
     }
 
-    pub fn set_overflow_bit(&mut self, set: bool) {
-        let addr = sfr_addr(&self.psw);
-        if set {
-            self.internal_memory.memory[addr as usize] |= 0x04;
+    fn register_addr(&self, n: u8) -> u8 {
+        (self.read_direct(sfr_addr(&self.psw)) & 0x18) + n
+    }
+
+    // Value of a byte operand .. Rn are plain direct addresses inside the active register bank
+    // Ports read as their pins, read-modify-write instructions use read_latch instead
+    fn read(&self, op: Operand) -> u8 {
+        match op {
+            Operand::Direct(addr) => self.read_port(addr),
+            _ => self.read_latch(op),
+        }
+    }
+
+    fn read_latch(&self, op: Operand) -> u8 {
+        match op {
+            Operand::A => self.acc(),
+            Operand::Direct(addr) => self.read_direct(addr),
+            Operand::Register(n) => self.read_direct(self.register_addr(n)),
+            Operand::Indirect(i) => self.read_indirect(self.read_direct(self.register_addr(i))),
+            Operand::Immediate(data) => data,
+            // Decoder never puts anything else where a byte is expected
+            _ => unreachable!("Operand {:?} is not a byte", op),
+        }
+    }
+
+    fn write(&mut self, op: Operand, val: u8) {
+        match op {
+            Operand::A => self.set_acc(val),
+            Operand::Direct(addr) => self.write_direct(addr, val),
+            Operand::Register(n) => self.write_direct(self.register_addr(n), val),
+            Operand::Indirect(i) => {
+                let addr = self.read_direct(self.register_addr(i));
+                self.write_indirect(addr, val);
+            }
+            _ => unreachable!("Operand {:?} can't be written", op),
+        }
+    }
+
+    fn acc(&self) -> u8 {
+        self.read_direct(sfr_addr(&self.accumulator))
+    }
+
+    fn set_acc(&mut self, val: u8) {
+        self.write_direct(sfr_addr(&self.accumulator), val);
+    }
+
+    fn carry(&self) -> bool {
+        self.read_bit(bits::CY)
+    }
+
+    fn dptr(&self) -> u16 {
+        let high = self.read_direct(sfr_addr(&SFR::Reg(IRegs::DPH))) as u16;
+        (high << 8) | self.read_direct(sfr_addr(&SFR::Reg(IRegs::DPL))) as u16
+    }
+
+    fn set_dptr(&mut self, val: u16) {
+        self.write_direct(sfr_addr(&SFR::Reg(IRegs::DPL)), val as u8);
+        self.write_direct(sfr_addr(&SFR::Reg(IRegs::DPH)), (val >> 8) as u8);
+    }
+
+    // External data memory address of MOVX, upper byte of @Ri comes from P2
+    fn xdata_addr(&self, op: Operand) -> u16 {
+        match op {
+            Operand::Indirect(i) => {
+                let high = self.read_direct(sfr_addr(&SFR::Port(Ports::P2))) as u16;
+                (high << 8) | self.read_direct(self.register_addr(i)) as u16
+            }
+            _ => self.dptr(),
+        }
+    }
+
+    // Stack lives in internal RAM and grows upwards, SP points at the last pushed byte
+    // PUSH, POP, calls and returns all go through these two so they share the same SP
+    pub fn push_stack(&mut self, val: u8) {
+        let sp_loc = sfr_addr(&SFR::Reg(IRegs::SP));
+        let sp = self.read_direct(sp_loc).wrapping_add(1);
+        self.write_direct(sp_loc, sp);
+        self.write_indirect(sp, val);
+    }
+
+    pub fn pop_stack(&mut self) -> u8 {
+        let sp_loc = sfr_addr(&SFR::Reg(IRegs::SP));
+        let sp = self.read_direct(sp_loc);
+        self.write_direct(sp_loc, sp.wrapping_sub(1));
+        self.read_indirect(sp)
+    }
+
+    // Return address is pushed low byte first, same as the hardware
+    pub fn call(&mut self, addr: u16) {
+        self.push_stack(self.pc as u8);
+        self.push_stack((self.pc >> 8) as u8);
+        self.pc = addr;
+    }
+
+    pub fn ret(&mut self) {
+        let high = self.pop_stack() as u16;
+        let low = self.pop_stack() as u16;
+        self.pc = (high << 8) | low;
+    }
+
+    // Instruction at the given address of code memory, without executing it
+    pub fn decode(&self, addr: u16) -> Instruction {
+        Instruction::decode(&self.code_memory, addr)
+    }
+
+    // Disassembly of count instructions starting from addr, one (address, instruction) per entry
+    pub fn disassemble(&self, addr: u16, count: usize) -> Vec<(u16, Instruction)> {
+        let mut addr = addr;
+        let mut out = Vec::with_capacity(count);
+        for _ in 0..count {
+            let instruction = self.decode(addr);
+            out.push((addr, instruction));
+            addr = addr.wrapping_add(instruction.size());
+        }
+        out
+    }
+
+    // Executes a single instruction at PC and returns the machine cycles it took
+    pub fn step(&mut self) -> u8 {
+        let opcode = self.code_memory[self.pc as usize];
+        let instruction = self.decode(self.pc);
+        // Jumps and MOVC @A+PC work from the address of the next instruction
+        self.pc = self.pc.wrapping_add(instruction.size());
+        self.execute(instruction);
+        self.set_parity_bit(self.acc());
+        let cycles = machine_cycles(opcode);
+        self.tick(cycles);
+        // Vectoring to an interrupt takes the cycles of an LCALL on top
+        let vectored = self.poll_interrupts();
+        self.tick(vectored);
+        cycles + vectored
+    }
+
+    // Peripherals advance once every machine cycle
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.sample_external_interrupts();
+            let t1_overflow = self.tick_timers();
+            let t2_overflows = self.tick_timer2();
+            self.tick_serial(t1_overflow, t2_overflows);
+        }
+    }
+
+    fn jump_if(&mut self, cond: bool, target: Operand) {
+        if let (true, Some(addr)) = (cond, target.target()) {
+            self.pc = addr;
+        }
+    }
+
+    fn execute(&mut self, instruction: Instruction) {
+        use Instruction::*;
+        use Operand::*;
+        match instruction {
+            Nop | Undefined(_) => {}
+            Ajmp(target) | Ljmp(target) | Sjmp(target) => self.jump_if(true, target),
+            Acall(target) | Lcall(target) => {
+                if let Some(addr) = target.target() {
+                    self.call(addr);
+                }
+            }
+            Ret => self.ret(),
+            Reti => self.return_from_interrupt(),
+            Jmp => self.pc = self.dptr().wrapping_add(self.acc() as u16),
+
+            // Rotates
+            Rr => self.set_acc(self.acc().rotate_right(1)),
+            Rl => self.set_acc(self.acc().rotate_left(1)),
+            Rrc => {
+                let acc = self.acc();
+                let carry = self.carry() as u8;
+                self.set_carry_bit(acc & 0x01 > 0);
+                self.set_acc((acc >> 1) | (carry << 7));
+            }
+            Rlc => {
+                let acc = self.acc();
+                let carry = self.carry() as u8;
+                self.set_carry_bit(acc & 0x80 > 0);
+                self.set_acc((acc << 1) | carry);
+            }
+            Swap => self.set_acc(self.acc().rotate_left(4)),
+
+            // INC and DEC
+            Inc(Dptr) => self.set_dptr(self.dptr().wrapping_add(1)),
+            Inc(op) => self.write(op, self.read_latch(op).wrapping_add(1)),
+            Dec(op) => self.write(op, self.read_latch(op).wrapping_sub(1)),
+
+            // Conditional jumps
+            Jbc(Bit(bit), target) => {
+                // JBC clears the bit when jumping
+                let set = self.read_bit(bit);
+                if set {
+                    self.write_bit(bit, false);
+                }
+                self.jump_if(set, target);
+            }
+            Jb(Bit(bit), target) => self.jump_if(self.pin(bit), target),
+            Jnb(Bit(bit), target) => self.jump_if(!self.pin(bit), target),
+            Jc(target) => self.jump_if(self.carry(), target),
+            Jnc(target) => self.jump_if(!self.carry(), target),
+            Jz(target) => self.jump_if(self.acc() == 0, target),
+            Jnz(target) => self.jump_if(self.acc() != 0, target),
+
+            // Arithmetic
+            Add(op) => self.add(self.read(op), false),
+            Addc(op) => self.add(self.read(op), true),
+            Subb(op) => self.subb(self.read(op)),
+            Mul => self.mul(),
+            Div => self.div(),
+            Da => self.decimal_adjust(),
+
+            // Boolean operations on the carry
+            Orl(C, Bit(bit)) => self.set_carry_bit(self.carry() | self.pin(bit)),
+            Orl(C, NotBit(bit)) => self.set_carry_bit(self.carry() | !self.pin(bit)),
+            Anl(C, Bit(bit)) => self.set_carry_bit(self.carry() & self.pin(bit)),
+            Anl(C, NotBit(bit)) => self.set_carry_bit(self.carry() & !self.pin(bit)),
+            Mov(C, Bit(bit)) => self.set_carry_bit(self.pin(bit)),
+            Mov(Bit(bit), C) => self.write_bit(bit, self.carry()),
+            Clr(C) => self.set_carry_bit(false),
+            Setb(C) => self.set_carry_bit(true),
+            Cpl(C) => self.set_carry_bit(!self.carry()),
+            Clr(Bit(bit)) => self.write_bit(bit, false),
+            Setb(Bit(bit)) => self.write_bit(bit, true),
+            Cpl(Bit(bit)) => self.write_bit(bit, !self.read_bit(bit)),
+
+            // Logical operations
+            Orl(dst, src) => self.write(dst, self.read_latch(dst) | self.read(src)),
+            Anl(dst, src) => self.write(dst, self.read_latch(dst) & self.read(src)),
+            Xrl(dst, src) => self.write(dst, self.read_latch(dst) ^ self.read(src)),
+            Clr(op) => self.write(op, 0x00),
+            Cpl(op) => self.write(op, !self.read_latch(op)),
+
+            // Data transfer
+            Mov(Dptr, Immediate16(data)) => self.set_dptr(data),
+            Mov(dst, src) => self.write(dst, self.read(src)),
+            Movc(src) => {
+                let base = match src {
+                    IndirectAccPc => self.pc,
+                    _ => self.dptr(),
+                };
+                let addr = base.wrapping_add(self.acc() as u16);
+                self.set_acc(self.code_memory[addr as usize]);
+            }
+            Movx(A, src) => {
+                let val = self.read_xdata(self.xdata_addr(src));
+                self.set_acc(val);
+            }
+            Movx(dst, _) => self.write_xdata(self.xdata_addr(dst), self.acc()),
+            Xch(op) => {
+                let val = self.read(op);
+                self.write(op, self.acc());
+                self.set_acc(val);
+            }
+            Xchd(op) => {
+                // Only the lower nibbles are exchanged
+                let val = self.read(op);
+                let acc = self.acc();
+                self.write(op, (val & 0xF0) | (acc & 0x0F));
+                self.set_acc((acc & 0xF0) | (val & 0x0F));
+            }
+            Push(op) => self.push_stack(self.read(op)),
+            Pop(op) => {
+                // POP SP gets the popped value, not the decremented one
+                let val = self.pop_stack();
+                self.write(op, val);
+            }
+
+            // Compare and decrement loops
+            Cjne(first, second, target) => {
+                let (first, second) = (self.read(first), self.read(second));
+                self.set_carry_bit(first < second);
+                self.jump_if(first != second, target);
+            }
+            Djnz(op, target) => {
+                let val = self.read_latch(op).wrapping_sub(1);
+                self.write(op, val);
+                self.jump_if(val != 0, target);
+            }
+            // Decoder only produces the combinations handled above
+            _ => unreachable!("Unhandled instruction {}", instruction),
+        }
+    }
+
+    // Runs the program until PC reaches the end address (10000H never matches) or the program settles on a jump to itself (SJMP $)
+    // with no interrupt left that could take it out of there
+    // Gives up after limit instructions and returns false, so that endless loops don't hang the caller
+    pub fn run_until(&mut self, end: u32, limit: usize) -> bool {
+        for _ in 0..limit {
+            if self.pc as u32 == end {
+                return true;
+            }
+            let pc = self.pc;
+            let instruction = self.decode(pc);
+            self.step();
+            // Only unconditional jumps count, returns and DJNZ can legitimately land on the same address
+            let is_jump = matches!(
+                instruction,
+                Instruction::Sjmp(_) | Instruction::Ljmp(_) | Instruction::Ajmp(_)
+            );
+            if is_jump && self.pc == pc && !self.interrupts_enabled() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// Arithmetic instructions and the flags they affect
+// P isn't touched here, it is recomputed from the accumulator after every instruction
+impl Sim8051 {
+    // CY is the carry out of bit 7, AC the carry out of bit 3 and OV is set when there's
+    // carry out of bit 6 or bit 7 but not from both
+    pub fn add(&mut self, operand: u8, with_carry: bool) {
+        let acc = self.acc();
+        let carry = (with_carry && self.carry()) as u8;
+        let sum = acc as u16 + operand as u16 + carry as u16;
+        let carry_from_3 = (acc & 0x0F) + (operand & 0x0F) + carry > 0x0F;
+        let carry_from_6 = (acc & 0x7F) + (operand & 0x7F) + carry > 0x7F;
+        self.set_acc(sum as u8);
+        self.set_carry_bit(sum > 0xFF);
+        self.set_auxiliary_carry_bit(carry_from_3);
+        self.set_overflow_bit(carry_from_6 ^ (sum > 0xFF));
+    }
+
+    // Same as add but with borrows, the previous carry is always subtracted
+    pub fn subb(&mut self, operand: u8) {
+        let acc = self.acc();
+        let borrow = self.carry() as u8;
+        let borrow_into_7 = (acc as u16) < operand as u16 + borrow as u16;
+        let borrow_into_3 = (acc & 0x0F) < (operand & 0x0F) + borrow;
+        let borrow_into_6 = (acc & 0x7F) < (operand & 0x7F) + borrow;
+        self.set_acc(acc.wrapping_sub(operand).wrapping_sub(borrow));
+        self.set_carry_bit(borrow_into_7);
+        self.set_auxiliary_carry_bit(borrow_into_3);
+        self.set_overflow_bit(borrow_into_6 ^ borrow_into_7);
+    }
+
+    // OV tells that the product doesn't fit in A alone
+    pub fn mul(&mut self) {
+        let b_loc = sfr_addr(&self.register_b);
+        let product = self.acc() as u16 * self.read_direct(b_loc) as u16;
+        self.set_carry_bit(false);
+        self.set_overflow_bit(product > 0xFF);
+        self.set_acc(product as u8);
+        self.write_direct(b_loc, (product >> 8) as u8);
+    }
+
+    // Division by zero sets OV and leaves A and B as they were
+    pub fn div(&mut self) {
+        let b_loc = sfr_addr(&self.register_b);
+        let (a, b) = (self.acc(), self.read_direct(b_loc));
+        self.set_carry_bit(false);
+        self.set_overflow_bit(false);
+        if let Some(quotient) = a.checked_div(b) {
+            self.set_acc(quotient);
+            self.write_direct(b_loc, a % b);
         } else {
-            self.internal_memory.memory[addr as usize] &= 0xFB;
+            self.set_overflow_bit(true);
+        }
+    }
+
+    // DA only ever sets CY, it never clears it .. so that multi byte BCD additions keep their carry
+    pub fn decimal_adjust(&mut self) {
+        let psw = self.read_direct(sfr_addr(&self.psw));
+        let mut acc = self.acc() as u16;
+        let mut carry = self.carry();
+        if (acc & 0x0F) > 9 || (psw & (1 << 6)) > 0 {
+            acc += 0x06;
+            carry |= acc > 0xFF;
+        }
+        if ((acc >> 4) & 0x0F) > 9 || carry {
+            acc += 0x60;
+            carry |= acc > 0xFF;
+        }
+        self.set_acc(acc as u8);
+        self.set_carry_bit(carry);
+    }
+}
+
+// Loading prebuilt firmware images (SDCC, Keil ...) straight into code memory
+// Images are validated completely before anything is written, so a bad file leaves code memory untouched
+// On success the address right after the highest loaded byte is returned, 10000H for an image reaching FFFFH
+impl Sim8051 {
+    pub fn load_hex(&mut self, hexfile: String, offset: u16) -> Result<u32, String> {
+        let text = std::fs::read_to_string(&hexfile)
+            .map_err(|err| format!("Cannot read hex file {} : {}", hexfile, err))?;
+        self.load_hex_from_string(&text, offset)
+    }
+
+    pub fn load_hex_from_string(&mut self, text: &str, offset: u16) -> Result<u32, String> {
+        let chunks = ihex::parse(text)?;
+        for (addr, bytes) in &chunks {
+            Self::check_fits(addr + offset as u32, bytes.len())?;
+        }
+        let mut end = offset as u32;
+        for (addr, bytes) in chunks {
+            let start = (addr + offset as u32) as usize;
+            self.code_memory[start..start + bytes.len()].copy_from_slice(&bytes);
+            end = end.max((start + bytes.len()) as u32);
+        }
+        Ok(end)
+    }
+
+    pub fn load_bin(&mut self, binfile: String, offset: u16) -> Result<u32, String> {
+        let bytes = std::fs::read(&binfile)
+            .map_err(|err| format!("Cannot read binary file {} : {}", binfile, err))?;
+        self.load_bin_from_bytes(&bytes, offset)
+    }
+
+    pub fn load_bin_from_bytes(&mut self, bytes: &[u8], offset: u16) -> Result<u32, String> {
+        Self::check_fits(offset as u32, bytes.len())?;
+        let start = offset as usize;
+        self.code_memory[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok((start + bytes.len()) as u32)
+    }
+
+    fn check_fits(start: u32, len: usize) -> Result<(), String> {
+        let end = start as usize + len;
+        if end > 64 * 1024 {
+            return Err(format!(
+                "{} bytes at {:04X}H do not fit in the 64K code memory",
+                len, start
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Machine cycles taken by each instruction .. one machine cycle is 12 oscillator periods
+pub fn machine_cycles(opcode: u8) -> u8 {
+    match opcode {
+        0x84 | 0xA4 => 4,
+        // AJMP and ACALL
+        op if op & 0x0F == 0x01 => 2,
+        0x02
+        | 0x10
+        | 0x12
+        | 0x20
+        | 0x22
+        | 0x30
+        | 0x32
+        | 0x40
+        | 0x43
+        | 0x50
+        | 0x53
+        | 0x60
+        | 0x63
+        | 0x70
+        | 0x72
+        | 0x73
+        | 0x75
+        | 0x80
+        | 0x82
+        | 0x83
+        | 0x85..=0x8F
+        | 0x90
+        | 0x92
+        | 0x93
+        | 0xA0
+        | 0xA3
+        | 0xA6..=0xAF
+        | 0xB0
+        | 0xB4..=0xBF
+        | 0xC0
+        | 0xD0
+        | 0xD5
+        | 0xD8..=0xDF
+        | 0xE0
+        | 0xE2
+        | 0xE3
+        | 0xF0
+        | 0xF2
+        | 0xF3 => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn program(src: &str) -> Sim8051 {
+        let mut asm = Assembler::default();
+        asm.read_src_from_string(src.to_string());
+        assert!(asm.assemble(), "{:?}", asm.diagnostics);
+        asm.simulator
+    }
+
+    fn flags(sim: &Sim8051) -> (bool, bool, bool) {
+        (
+            sim.read_bit(bits::CY),
+            sim.read_bit(bits::AC),
+            sim.read_bit(bits::OV),
+        )
+    }
+
+    #[test]
+    fn arithmetic_flags() {
+        // (instruction, A, operand, CY before, A after, (CY, AC, OV) after)
+        let cases = [
+            ("ADD", 0x7F, 0x01, false, 0x80, (false, true, true)),
+            ("ADD", 0xFF, 0x01, false, 0x00, (true, true, false)),
+            ("ADD", 0x80, 0x80, false, 0x00, (true, false, true)),
+            ("ADD", 0x0F, 0x01, true, 0x10, (false, true, false)),
+            ("ADDC", 0xFF, 0x00, true, 0x00, (true, true, false)),
+            ("ADDC", 0x7E, 0x01, true, 0x80, (false, true, true)),
+            ("SUBB", 0x80, 0x01, false, 0x7F, (false, true, true)),
+            ("SUBB", 0x00, 0x01, false, 0xFF, (true, true, false)),
+            ("SUBB", 0x7F, 0xFF, false, 0x80, (true, false, true)),
+            ("SUBB", 0x10, 0x0F, true, 0x00, (false, true, false)),
+        ];
+        for (name, a, operand, carry, result, expected) in cases {
+            let mut sim = Sim8051::default();
+            sim.set_acc(a);
+            sim.set_carry_bit(carry);
+            match name {
+                "ADD" => sim.add(operand, false),
+                "ADDC" => sim.add(operand, true),
+                _ => sim.subb(operand),
+            }
+            let case = format!("{} {:02X}H, {:02X}H with CY {}", name, a, operand, carry);
+            assert_eq!(sim.acc(), result, "{}", case);
+            assert_eq!(flags(&sim), expected, "{}", case);
+        }
+    }
+
+    #[test]
+    fn decimal_adjust_after_add() {
+        let mut sim = Sim8051::default();
+        sim.set_acc(0x99);
+        sim.add(0x01, false);
+        sim.decimal_adjust();
+        assert_eq!(sim.acc(), 0x00);
+        assert!(sim.carry());
+
+        sim.set_acc(0x38);
+        sim.add(0x38, false);
+        sim.decimal_adjust();
+        assert_eq!(sim.acc(), 0x76);
+        assert!(!sim.carry());
+    }
+
+    #[test]
+    fn parity_follows_acc_after_mov_psw() {
+        let mut sim = program("mov a, #03h\nmov psw, #0FFh\nmov a, #01h\nmov psw, #00h\n");
+        sim.step();
+        sim.step();
+        assert!(!sim.read_bit(bits::P));
+        assert_eq!(sim.read_direct(sfr_addr(&sim.psw)), 0xFE);
+        sim.step();
+        sim.step();
+        assert!(sim.read_bit(bits::P));
+    }
+
+    #[test]
+    fn indirect_addressing_reaches_upper_ram_not_sfrs() {
+        let mut sim = program("mov r0, #90h\nmov @r0, #55h\nmov a, @r0\nmov r1, 90h\n");
+        for _ in 0..4 {
+            sim.step();
+        }
+        assert_eq!(sim.internal_memory.memory[0x90], 0x55);
+        assert_eq!(sim.acc(), 0x55);
+        // P1 is untouched
+        assert_eq!(sim.read_direct(0x90), 0xFF);
+        assert_eq!(sim.get_active_register_bank().ptr[1], 0xFF);
+    }
+
+    #[test]
+    fn stack_in_upper_ram() {
+        let mut sim = program(
+            "mov b, #0AAh\nmov sp, #0EFh\nmov a, #12h\npush acc\nlcall sub\nsjmp $\nsub: pop 7\npop 6\npop b\n",
+        );
+        for _ in 0..8 {
+            sim.step();
         }
+        // B at F0H is an SFR, the pushed byte went to RAM at F0H
+        assert_eq!(sim.internal_memory.memory[0xF0], 0x12);
+        assert_eq!(sim.read_direct(sfr_addr(&SFR::Reg(IRegs::B))), 0x12);
+        // Return address of LCALL, low byte first
+        assert_eq!(sim.internal_memory.memory[0xF1..0xF3], [0x0D, 0x00]);
+        assert_eq!(sim.read_direct(sfr_addr(&SFR::Reg(IRegs::SP))), 0xEF);
     }
 }
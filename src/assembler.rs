@@ -1,61 +1,132 @@
-use std::{fs::File, io::Read};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    ops::Range,
+};
 
 use crate::{
+    diagnostic::{Diagnostic, Severity},
+    expr, ihex,
+    instruction::{Instruction, Operand},
     lexer::{self, Tokenizer},
-    Sim8051::{self, InternalMemory},
+    Sim8051,
 };
 
-// Here we will have fetch decode and the execute cycle
-// We will continue parser here too .. Since regex is finished, need to device own parser
-
-//  start   :-    ^   program   $
+// Assembler for the 8051 .. source is assembled into machine code and loaded into code memory of the simulator
+// Fetch, decode and execute cycle lives in the simulator itself
 //
-//  program :-    stmt
-//          |     label stmt
-//          |     e
+// Every line of the source has the form
 //
-//
-//  stmt    :-    comma id sstmt
+//  line    :-    label: mnemonic operands
+//          |     mnemonic operands
+//          |     label:
+//          |     name directive value     (equ, set, bit, data, idata, xdata, code)
 //          |     e
 //
-//  sstmt   :-    comma id tstmt
-//          |     e
-//
-//  tstmt   :-    comma id
-//          |     e
-//
-//  This grammar should work for parsing the assembler .. parsing comment isn't considered
-//
-//
+//  operands :-   operand , operands
+//           |    operand
+//           |    e
 //
+//  org, db, dw and ds place code and data, assembling stops at the end directive
+//  Anything after ; is a comment. Mnemonics, directives, registers and SFR names can be in any case,
+//  the symbols defined in the source are case sensitive
 
-use std::collections::HashMap; // or map? either way duplicated entries are to be discarded or warned
+use std::collections::{HashMap, HashSet}; // or map? either way duplicated entries are to be discarded or warned
 
-pub struct Assembler {
-    pub simulator: Sim8051::Sim8051,
-    pub tokenizer: lexer::Tokenizer,
-    pub jmptable: HashMap<String, usize>, // represents label and their position on the source file for quick jumping
+// Upper bound on the instructions executed by start, so that programs stuck in a loop don't hang the caller
+pub const STEP_LIMIT: usize = 1_000_000;
+
+// Object bytes shown on a single row of the listing
+const LISTING_BYTES_PER_ROW: usize = 4;
+
+// What a symbol stands for .. labels are code addresses, the rest come from the defining directives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SymbolKind {
+    Code,
+    Data,
+    Idata,
+    Xdata,
+    Bit,
+    Number, // EQU
+    Set,    // like EQU, but can be redefined further down the source
+}
+
+impl SymbolKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Code => "CODE",
+            Self::Data => "DATA",
+            Self::Idata => "IDATA",
+            Self::Xdata => "XDATA",
+            Self::Bit => "BIT",
+            Self::Number => "NUMBER",
+            Self::Set => "SET",
+        }
+    }
 }
 
-impl Default for Assembler {
-    fn default() -> Assembler {
-        Assembler {
-            simulator: Sim8051::Sim8051::default(),
-            tokenizer: lexer::Tokenizer::default(),
-            jmptable: HashMap::new(),
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub value: u16,
+    pub kind: SymbolKind,
+}
+
+// A single source line as assembled by the second pass, used for the listing and the hex output
+#[derive(Debug, Default)]
+pub struct ListingLine {
+    pub line: usize,       // line number in the source, starting from 1
+    pub addr: Option<u16>, // code address of the line, none for lines that were not assembled
+    pub bytes: Vec<u8>,    // bytes emitted for the line
+    pub text: String,      // the line exactly as in the source
+    pub error: Option<String>,
+}
+
+// Error in a statement, with the index of the operand it is about when there is one
+#[derive(Debug)]
+struct AsmError {
+    msg: String,
+    operand: Option<usize>,
+}
+
+impl AsmError {
+    fn at(operand: usize, msg: String) -> AsmError {
+        AsmError {
+            msg,
+            operand: Some(operand),
         }
     }
 }
 
+impl From<String> for AsmError {
+    fn from(msg: String) -> AsmError {
+        AsmError { msg, operand: None }
+    }
+}
+
+#[derive(Default)]
+pub struct Assembler {
+    pub simulator: Sim8051::Sim8051,
+    pub tokenizer: lexer::Tokenizer,
+    pub symbols: HashMap<String, Symbol>, // labels and the symbols from EQU, DATA, BIT ..
+    pub end_address: u32, // address right after the last assembled instruction, up to 10000H
+    pub listing: Vec<ListingLine>, // every source line of the last assembling with the bytes emitted for it
+    pub file: String,              // name of the source file, used in the diagnostics
+    pub diagnostics: Vec<Diagnostic>, // errors and warnings of the last assembling and run
+    final_pass: bool, // errors are only reported, and forward references only resolved, in the second pass
+    location: u16,    // address of the statement being assembled, the $ of expressions
+    defined: HashSet<String>, // symbols defined so far in the current pass
+    backward_only: bool, // ORG and DS must come out the same in both passes, so they can't look ahead
+    first_pass_ends: HashMap<usize, u32>, // location counter after each statement of the first pass
+}
+
 impl Assembler {
     // This is the beginning of the parser
     pub fn read_src(&mut self, srcfile: String) -> bool {
-        let mut src = File::open(srcfile);
+        let src = File::open(&srcfile);
         match src {
             Ok(mut file) => {
-                file.read_to_string(&mut self.tokenizer.src)
-                    .expect("Failed to read the open file");
-                true
+                self.file = srcfile;
+                file.read_to_string(&mut self.tokenizer.src).is_ok()
             }
             Err(_) => false,
         }
@@ -65,1273 +136,954 @@ impl Assembler {
         self.tokenizer.src = src;
     }
 
-    pub fn show_jmptable(&self) {
-        println!("-------------------------------------------------- Showing jmp table of assembler ----------------------------------------");
-        for (name, pos) in &self.jmptable {
-            println!("{:<15} -> {:<10}", name, pos);
+    pub fn show_symbols(&self) {
+        println!("-------------------------------------------------- Showing symbol table of assembler ----------------------------------------");
+        for (name, sym) in &self.symbols {
+            println!("{:<15} -> {:#06x} {}", name, sym.value, sym.kind.name());
         }
     }
 
-    pub fn collect_labels(&mut self) {
-        self.tokenizer.pos = 1;
-        let mut token;
-
-        loop {
-            token = self.tokenizer.parse_all_as_id();
-            match token {
-                Some(tok) => {
-                    if let lexer::TokenType::ID(val) = &tok.token {
-                        if val.contains(':') {
-                            let labelname: String = val.chars().take_while(|x| *x != ':').collect();
-                            self.jmptable.insert(labelname, self.tokenizer.pos);
-                        }
-                    }
-                    self.tokenizer.pos += tok.len;
-                }
-                None => {
-                    if self.tokenizer.src.chars().skip(self.tokenizer.pos).next() == Some(',') {
-                        self.tokenizer.pos += 1;
-                    } else {
-                        break;
-                    }
-                }
-            }
+    // Intel HEX image of the assembled code, only the bytes actually emitted are included
+    // Nothing goes past FFFFH, exactly like in code memory
+    pub fn to_hex(&self) -> String {
+        let chunks: Vec<(u32, Vec<u8>)> = self
+            .listing
+            .iter()
+            .filter_map(|x| {
+                let addr = x.addr? as usize;
+                let len = x.bytes.len().min(0x10000 - addr);
+                Some((addr as u32, x.bytes[..len].to_vec()))
+            })
+            .collect();
+        ihex::write(&chunks)
+    }
+
+    pub fn write_hex(&self, hexfile: String) -> bool {
+        match File::create(hexfile) {
+            Ok(mut file) => file.write_all(self.to_hex().as_bytes()).is_ok(),
+            Err(_) => false,
         }
-        self.tokenizer.pos = 0;
     }
 
-    pub fn start(&mut self) {
-        self.collect_labels();
-        let mut ptr = self.tokenizer.src.chars();
-        // do fist pass to collect all the labels
-        if let Some(ch) = ptr.next() {
-            if ch != '^' {
-                println!("Unexpected character at the beginning of the file");
-                return;
+    // Listing of the assembled source in the usual assembler layout
+    //  LOC   OBJ          LINE   SOURCE
+    // Instructions longer than 4 bytes continue on the next row, errors are printed below their line
+    // The symbol table sorted by name follows the source
+    pub fn to_listing(&self) -> String {
+        let mut out = String::new();
+        out.push_str("LOC   OBJ          LINE   SOURCE\n\n");
+        for line in &self.listing {
+            let mut rows = line.bytes.chunks(LISTING_BYTES_PER_ROW);
+            let loc = match line.addr {
+                Some(addr) => format!("{:04X}", addr),
+                None => String::new(),
+            };
+            let obj = rows.next().map(hex_bytes).unwrap_or_default();
+            out.push_str(&format!(
+                "{:<4}  {:<12} {:>4}   {}\n",
+                loc, obj, line.line, line.text
+            ));
+
+            let mut addr = line.addr.unwrap_or(0);
+            for row in rows {
+                addr = addr.wrapping_add(LISTING_BYTES_PER_ROW as u16);
+                out.push_str(&format!("{:04X}  {}\n", addr, hex_bytes(row)));
+            }
+            if let Some(err) = &line.error {
+                out.push_str(&format!("*** ERROR : {}\n", err));
             }
-        } else {
-            return;
         }
-        self.tokenizer.pos += 1;
-        self.prog();
+
+        let mut symbols: Vec<(&String, &Symbol)> = self.symbols.iter().collect();
+        symbols.sort_by_key(|x| x.0);
+        out.push_str("\n\nSYMBOL TABLE\n\n");
+        for (name, sym) in symbols {
+            out.push_str(&format!(
+                "{:<20} {:<7} {:04X}H\n",
+                name,
+                sym.kind.name(),
+                sym.value
+            ));
+        }
+        out
     }
 
-    // This function is the main core of the parser
-    // I guess LL(1) grammar should work fine
-    fn prog(&mut self) {
-        // It can be either stmt or label with stmt or empty
-        let mut ptr = self
-            .tokenizer
-            .src
-            .chars()
-            .skip(self.tokenizer.pos)
-            .skip_while(|x| x.is_ascii_whitespace());
-        if let Some(ch) = ptr.next() {
-            if ch == '$' {
-                return;
-            }
+    pub fn write_listing(&self, lstfile: String) -> bool {
+        match File::create(lstfile) {
+            Ok(mut file) => file.write_all(self.to_listing().as_bytes()).is_ok(),
+            Err(_) => false,
         }
-        // we should be parsing the string here and returns token as id or string of chracter.. not as actual token type due to various addressing modes and their different semantics
-        let token = self.tokenizer.parse_all_as_id();
-        // differentiate this token as label or as id
-        match token {
-            None => println!("Unexpected token found here"),
-
-            Some(tok) => {
-                if let lexer::TokenType::ID(val) = &tok.token {
-                    if val.contains(':') {
-                        self.tokenizer.pos += tok.len;
-                        // Read another token now
-                        let newtoken = self.tokenizer.parse_all_as_id();
-                        if let Some(z) = newtoken {
-                            if self.stmt(&z) {
-                                self.prog()
-                            };
-                        } else {
-                            println!("Invalid token here : ");
-                        }
-                    } else {
-                        // It has to be id
-                        if self.stmt(&tok) {
-                            self.prog();
-                        }
-                    }
-                }
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|x| x.is_error())
+    }
+
+    // Assembles the source and runs it from address 0000H until it runs past the last instruction
+    // Nothing is run if there are errors, the diagnostics tell what went wrong
+    pub fn start(&mut self) -> bool {
+        if !self.assemble() {
+            return false;
+        }
+        self.simulator.set_pc(0x0000);
+        if !self.simulator.run_until(self.end_address, STEP_LIMIT) {
+            // Blame the line the program was stuck at
+            let pc = self.simulator.pc();
+            let line = self
+                .listing
+                .iter()
+                .filter(|x| x.addr.is_some_and(|addr| addr <= pc))
+                .max_by_key(|x| x.addr)
+                .map(|x| (x.line, x.text.clone()));
+            if let Some((line, text)) = line {
+                let msg = format!(
+                    "Program still running after {} instructions, stopped at PC = {:04X}H",
+                    STEP_LIMIT, pc
+                );
+                self.report(Severity::Warning, line, &text, statement_span(&text), msg);
             }
         }
+        true
     }
+}
 
-    fn stmt(&mut self, prev_token: &lexer::Token) -> bool {
-        // This token better be
-        // This func now should decide to return or call the second statement
-        // This resulting token better be a asm instruction
-        // Lets differ match, lets just try parsing
-        self.tokenizer.pos += prev_token.len;
-
-        // else its the single instruction command .. execute it right here
-        // use ad hoc context sensitiveness here
-
-        if let lexer::TokenType::ID(ins) = &prev_token.token {
-            let pass = match ins.as_str() {
-                // list all single instructions here
-                "ret" => {
-                    let returnpos = self.jmptable.get(&String::from("ret"));
-                    if let Some(&pos) = returnpos {
-                        self.jmptable.remove(&String::from("ret"));
-                        self.tokenizer.pos = pos;
-                    }
-                    println!("Found ret command");
-                    true
+// Actual assembler that turns the source into 8051 machine code
+// First pass walks the source to find out the address of every label, second pass emits the opcodes into code memory
+impl Assembler {
+    pub fn assemble(&mut self) -> bool {
+        self.symbols.clear();
+        self.listing.clear();
+        self.diagnostics.clear();
+        // Errors are only reported on the second pass, first one only needs the instruction sizes
+        // Symbols defined twice are caught in the first pass though, as the second one just updates them
+        self.final_pass = false;
+        self.run_pass();
+        self.final_pass = true;
+        self.run_pass();
+        self.diagnostics.sort_by_key(|x| x.line);
+        !self.has_errors()
+    }
+
+    fn run_pass(&mut self) {
+        let src = self.tokenizer.src.clone();
+        // Kept wider than an address so that running past FFFFH can be caught
+        let mut addr: u32 = 0x0000;
+        self.defined.clear();
+        if !self.final_pass {
+            self.first_pass_ends.clear();
+        }
+
+        for (n, text) in src.lines().enumerate() {
+            let mut entry = ListingLine {
+                line: n + 1,
+                text: text.trim_end().to_string(),
+                ..Default::default()
+            };
+            let line = text.trim();
+            if line.is_empty() {
+                self.add_listing(entry);
+                continue;
+            }
+            let stmt = Tokenizer::parse_statement(line);
+            self.location = addr as u16;
+
+            if let Some(label) = stmt.label {
+                if self.final_pass && addr > 0xFFFF {
+                    let msg = format!("label {} is past the end of code memory at FFFFH", label);
+                    self.report(Severity::Error, n + 1, text, word_span(text, &label), msg);
                 }
-                "nop" => {
-                    // Does nothing
-                    true
+                let addr = addr as u16;
+                let sym = Symbol {
+                    value: addr,
+                    kind: SymbolKind::Code,
+                };
+                let first = self.defined.insert(label.clone());
+                if !self.final_pass && self.symbols.contains_key(&label) {
+                    let msg = format!("label {} defined more than once", label);
+                    self.report(Severity::Error, n + 1, text, word_span(text, &label), msg);
+                } else if !self.final_pass {
+                    self.symbols.insert(label.clone(), sym);
                 }
-                "end" => {
-                    println!("\n\nReached at the end of the program\n");
-                    false
+                // Labels only get their address in the first pass, the second one has to agree with it
+                let moved = self
+                    .symbols
+                    .get(&label)
+                    .filter(|x| x.kind == SymbolKind::Code && x.value != addr);
+                if let (true, true, Some(old)) = (self.final_pass, first, moved) {
+                    let msg = format!(
+                        "Phase error, label {} is at {:04X}H but was at {:04X}H in the first pass",
+                        label, addr, old.value
+                    );
+                    self.report(Severity::Error, n + 1, text, word_span(text, &label), msg);
                 }
-                _ => {
-                    // Move toward another branch
-                    self.sstmt(&ins)
+            }
+
+            let mnemonic = match stmt.mnemonic {
+                Some(mnemonic) => mnemonic,
+                None => {
+                    self.add_listing(entry);
+                    continue;
                 }
             };
-            pass
-        } else {
-            println!("Unexpected token found here in stmt branch...");
-            false
-        }
-    }
-
-    fn sstmt(&mut self, command: &str) -> bool {
-        // This function carries the previous command and is ready to parse the next operand
-        // Now get the first argument from here
-        let next_token = self.tokenizer.parse_all_as_id();
-        // It better be a id
-        match next_token {
-            None => false,
-            Some(token) => {
-                // It can be anything now .. R0, @R0, 22H, P0.1 like these
-                if let lexer::TokenType::ID(ins) = &token.token {
-                    self.tokenizer.pos += token.len;
-                    // if it successfully passed get ready for another statement
-                    if self.tokenizer.consume_comma() {
-                        self.tstmt(command, ins)
-                    } else {
-                        // check for single instruction command here
-                        match command {
-                            // since clr,  setb and cpl are quite similar, they can be merged
-                            "clr" => clr_set_cpl(
-                                self,
-                                command,
-                                ins.as_str(),
-                                InternalMemory::reset_bit_addressable,
-                            ),
-                            "cpl" => clr_set_cpl(
-                                self,
-                                command,
-                                ins.as_str(),
-                                InternalMemory::complement_bit_addressable,
-                            ),
-                            "setb" => clr_set_cpl(
-                                self,
-                                command,
-                                ins.as_str(),
-                                InternalMemory::set_bit_addressable,
-                            ),
-                            "swap" => {
-                                match ins.as_str() {
-                                    "A" => {
-                                        // Swap Nibbles of A the accumulator
-                                        let loc =
-                                            Sim8051::sfr_addr(&self.simulator.accumulator) as usize;
-                                        let val = self.simulator.internal_memory.memory[loc];
-                                        self.simulator.internal_memory.memory[loc] =
-                                            ((val & 0x0F) << 4) | ((val & 0xF0) >> 4);
-                                        true
-                                    }
-                                    _ => {
-                                        println!("Invalid argument to swap instruction .. Expected accumulator");
-                                        false
-                                    }
-                                }
-                            }
-                            "rl" => {
-                                match ins.as_str() {
-                                    "A" => {
-                                        // Naive implementation
-                                        let acc = Sim8051::sfr_addr(&self.simulator.accumulator);
-                                        let val =
-                                            self.simulator.internal_memory.memory[acc as usize];
-                                        self.simulator.internal_memory.memory[acc as usize] =
-                                            ((val & 0x80) >> 7) | (val << 1);
-                                        true
-                                    }
-                                    _ => {
-                                        println!("Invalid operand to rl");
-                                        false
-                                    }
-                                }
-                            }
-                            "rr" => {
-                                match ins.as_str() {
-                                    "A" => {
-                                        // TODO
-                                        let acc = Sim8051::sfr_addr(&self.simulator.accumulator);
-                                        let val =
-                                            self.simulator.internal_memory.memory[acc as usize];
-                                        self.simulator.internal_memory.memory[acc as usize] =
-                                            ((val & 0x01) << 7) | (val >> 1);
-                                        true
-                                    }
-                                    _ => {
-                                        println!("Invalid argument to rr");
-                                        false
-                                    }
-                                }
-                            }
-                            "rlc" => {
-                                match ins.as_str() {
-                                    "A" => {
-                                        // Get the content of the carry flag first
-                                        let psw = Sim8051::sfr_addr(&self.simulator.psw);
-                                        let cflag = (self.simulator.internal_memory.memory
-                                            [psw as usize]
-                                            & 0x80)
-                                            >> 7;
-
-                                        let acc = Sim8051::sfr_addr(&self.simulator.accumulator);
-                                        let val =
-                                            self.simulator.internal_memory.memory[acc as usize];
-
-                                        let acc_msb = val >> 7;
-                                        if acc_msb == 1 {
-                                            self.simulator.internal_memory.memory[psw as usize] |=
-                                                0x80;
-                                        } else {
-                                            self.simulator.internal_memory.memory[psw as usize] &=
-                                                0x7F;
-                                        }
-                                        self.simulator.internal_memory.memory[acc as usize] =
-                                            (val << 1) | cflag;
-                                        true
-                                    }
-                                    _ => {
-                                        println!("Invalid argument to rr");
-                                        false
-                                    }
-                                }
-                            }
-                            "rrc" => {
-                                match ins.as_str() {
-                                    "A" => {
-                                        // Get the content of the carry flag first
-                                        let psw = Sim8051::sfr_addr(&self.simulator.psw);
-                                        let cflag = (self.simulator.internal_memory.memory
-                                            [psw as usize]
-                                            & 0x80)
-                                            >> 7;
-
-                                        let acc = Sim8051::sfr_addr(&self.simulator.accumulator);
-                                        let val =
-                                            self.simulator.internal_memory.memory[acc as usize];
-
-                                        let acc_lsb = val & 0x01;
-                                        if acc_lsb == 1 {
-                                            self.simulator.internal_memory.memory[psw as usize] |=
-                                                0x80;
-                                        } else {
-                                            self.simulator.internal_memory.memory[psw as usize] &=
-                                                0x7F;
-                                        }
-                                        self.simulator.internal_memory.memory[acc as usize] =
-                                            (val >> 1) | (cflag << 7);
-                                        true
-                                    }
-                                    _ => {
-                                        println!("Invalid argument to rr");
-                                        false
-                                    }
-                                }
-                            }
-                            "jc" | "jnc" | "jz" | "jnz" => {
-                                let pos = self.jmptable.get(ins);
-                                if let Some(&val) = &pos {
-                                    // TODO :: Check for the carry bit and jump here
-                                    let jmp_condition = if command == "jc" || command == "jnc" {
-                                        // check carry bit '
-                                        let carry_set = (self.simulator.internal_memory.memory
-                                            [Sim8051::sfr_addr(&self.simulator.psw) as usize]
-                                            & 0x80)
-                                            > 0;
-                                        if carry_set && command == "jc" {
-                                            true
-                                        } else if !carry_set && command == "jnc" {
-                                            true
-                                        } else {
-                                            false
-                                        }
-                                    } else {
-                                        let is_acc_zero = (self.simulator.internal_memory.memory
-                                            [Sim8051::sfr_addr(&self.simulator.accumulator)
-                                                as usize])
-                                            == 0;
-                                        if is_acc_zero && command == "jz" {
-                                            true
-                                        } else if !is_acc_zero && command == "jnz" {
-                                            true
-                                        } else {
-                                            false
-                                        }
-                                    };
-                                    if jmp_condition {
-                                        self.tokenizer.pos = val;
-                                    };
-                                    true
-                                } else {
-                                    println!("Invalid label found {}", ins);
-                                    false
-                                }
-                            }
-                            // haven't explored pattern binding in or pattern
-                            // TODO :: DPTR for inc
-                            inst @ "dec" | inst @ "inc" => {
-                                let step = if inst == "dec" { -1 } else { 1 };
-                                // go through all the addressing modes ..
-                                // continuing further in the belief of rust using 2's complement for their negative number
-                                let memloc = if ins == "A" {
-                                    Some(Sim8051::sfr_addr(&self.simulator.accumulator))
-                                } else {
-                                    if let Some(operand) = lexer::Tokenizer::parse_all(ins.as_str())
-                                    {
-                                        use lexer::TokenType::*;
-                                        match operand.token {
-                                            IMM(_) => {
-                                                println!(
-                                                    "Cannot use immediate value as operand to {}",
-                                                    inst
-                                                );
-                                                None
-                                            }
-                                            HEX(loc) => Some(loc as u8),
-                                            ID(reg) => {
-                                                use std::str::FromStr;
-                                                // Direct register location
-                                                let reg = Sim8051::ScratchpadRegisters::from_str(
-                                                    reg.as_str(),
-                                                )
-                                                .expect("Not a scratchpad register.. error");
-                                                // Return its location depending upon the currently selected register bank
-                                                let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                                    Sim8051::IRegs::PSW,
-                                                ))
-                                                    as usize;
-                                                let count: u8 = (0x18
-                                                    & self.simulator.internal_memory.memory
-                                                        [pswloc])
-                                                    >> 3;
-                                                Some(count * 8 + reg.reg_count())
-                                            }
-                                            IND(reg) => {
-                                                let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                                    Sim8051::IRegs::PSW,
-                                                ))
-                                                    as usize;
-                                                let count: u8 = (0x18
-                                                    & self.simulator.internal_memory.memory
-                                                        [pswloc])
-                                                    >> 3;
-                                                Some(
-                                                    self.simulator.internal_memory.memory
-                                                        [(count * 8 + reg.reg_count()) as usize],
-                                                )
-                                            }
-                                            _ => None,
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                };
-                                // get the content of that memory location as i16 first and then do some casting and manipulation here and there
-                                let val = self.simulator.internal_memory.memory
-                                    [memloc.unwrap() as usize]
-                                    as i16;
-                                let ans = ((val + step) & 0xFF) as u8;
-                                self.simulator.internal_memory.memory[memloc.unwrap() as usize] =
-                                    ans;
-                                true
-                            }
-                            ch @ "ajmp" | ch @ "acall" => {
-                                // just jmp to the label from here
-                                // How should it change the syntax parsing process?
-                                let label = String::from(ins);
-                                let pos = self.jmptable.get(&label);
-                                if let Some(&y) = pos {
-                                    if ch == "ajmp" {
-                                        self.tokenizer.pos = y;
-                                    } else {
-                                        // push current position for return statement
-                                        // using ret .. too bored to change datastructure
-                                        self.jmptable
-                                            .insert(String::from("ret"), self.tokenizer.pos);
-                                        self.tokenizer.pos = y;
-                                    }
-                                    true
-                                } else {
-                                    println!("\nInvalid {} command : {} not found.", ch, label);
-                                    true
-                                }
-                            }
-                            ch @ "push" | ch @ "pop" => {
-                                // The addressing modes of the push and pop operations aren't cleared from Keil documentation
-                                // TODO:: So, we only allowing registers and accumulator to be pushed into for now
-                                //
-                                if let Some(op) = lexer::Tokenizer::parse_all(ins) {
-                                    use lexer::TokenType::*;
-                                    let src_addr = match op.token {
-                                        HEX(hex) => Some(hex as u8),
-                                        ID(id) => {
-                                            if id == "A" {
-                                                Some(Sim8051::sfr_addr(&self.simulator.accumulator))
-                                            } else if id == "B" {
-                                                Some(Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                                    Sim8051::IRegs::B,
-                                                )))
-                                            } else {
-                                                use std::str::FromStr;
-                                                match Sim8051::ScratchpadRegisters::from_str(&id) {
-                                                    Ok(reg) => {
-                                                        let count: u8 = (0x18
-                                                            & self
-                                                                .simulator
-                                                                .internal_memory
-                                                                .memory
-                                                                [Sim8051::sfr_addr(
-                                                                    &self.simulator.psw,
-                                                                )
-                                                                    as usize])
-                                                            >> 3;
-                                                        Some(count * 8 + reg.reg_count())
-                                                    }
-                                                    Err(_) => {
-                                                        //
-                                                        println!(
-                                                            "Invalid operand to {} -> {}.",
-                                                            ins, ch
-                                                        );
-                                                        None
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        _ => None,
-                                    };
-                                    // if it is push, retrieve its content
-                                    let sp_loc =
-                                        Sim8051::sfr_addr(&Sim8051::SFR::Reg(Sim8051::IRegs::SP))
-                                            as usize;
-                                    let sp_val = self.simulator.internal_memory.memory[sp_loc];
-                                    if ch == "push" {
-                                        // cannot use reference to that memory due to borro
-                                        self.simulator.internal_memory.memory[sp_loc] += 1;
-                                        self.simulator.internal_memory.memory[self
-                                            .simulator
-                                            .internal_memory
-                                            .memory[sp_loc]
-                                            as usize] = self.simulator.internal_memory.memory
-                                            [src_addr.unwrap() as usize];
-                                    } else {
-                                        self.simulator.internal_memory.memory
-                                            [src_addr.unwrap() as usize] =
-                                            self.simulator.internal_memory.memory[self
-                                                .simulator
-                                                .internal_memory
-                                                .memory[sp_loc]
-                                                as usize];
-                                        self.simulator.internal_memory.memory[sp_loc] -= 1;
-                                    }
-                                    true
-                                } else {
-                                    println!("Invalid operand {} to {}.", ins, ch);
-                                    false
-                                }
-                            }
+            let ops: Vec<&str> = stmt.operands.iter().map(|x| x.as_str()).collect();
 
-                            ch @ "mul" | ch @ "div" => {
-                                if ins == "AB" {
-                                    let op1_addr =
-                                        Sim8051::sfr_addr(&Sim8051::SFR::Reg(Sim8051::IRegs::B));
-                                    let op2_addr = Sim8051::sfr_addr(&self.simulator.accumulator);
-                                    let psw_loc = Sim8051::sfr_addr(&self.simulator.psw);
-                                    // Reset the carry flag
-                                    self.simulator.internal_memory.memory[psw_loc as usize] &= 0x7F;
-                                    // Reset the overflow flag
-                                    self.simulator.internal_memory.memory[psw_loc as usize] &= 0xFB;
-                                    if ch == "mul" {
-                                        let product: u16 = self.simulator.internal_memory.memory
-                                            [op1_addr as usize]
-                                            as u16
-                                            * self.simulator.internal_memory.memory
-                                                [op2_addr as usize]
-                                                as u16;
-                                        if (product & 0xFF00) > 1 {
-                                            // set overflow flag
-                                            self.simulator.internal_memory.memory
-                                                [psw_loc as usize] |= 0x04;
-                                        }
-
-                                        self.simulator.internal_memory.memory[op2_addr as usize] =
-                                            (product & 0x00FF) as u8;
-                                        self.simulator.internal_memory.memory[op1_addr as usize] =
-                                            ((product & 0xFF00) >> 8) as u8;
-                                        // Its not specified whose parity bit is taken.. Assuming its the accumulator's
-                                        self.simulator.set_parity_bit(
-                                            self.simulator.internal_memory.memory
-                                                [op1_addr as usize],
-                                        );
-                                    } else {
-                                        let a = self.simulator.internal_memory.memory
-                                            [op2_addr as usize];
-                                        let b = self.simulator.internal_memory.memory
-                                            [op1_addr as usize];
-
-                                        if b == 0x00 {
-                                            self.simulator.internal_memory.memory
-                                                [psw_loc as usize] |= 0x04;
-                                            println!("Warning : Division by zero attempted");
-                                        } else {
-                                            self.simulator.internal_memory.memory
-                                                [op2_addr as usize] = a / b;
-                                            self.simulator.internal_memory.memory
-                                                [op1_addr as usize] = a % b;
-                                        }
-                                    }
-                                    true
-                                } else {
-                                    println!("Invalid operand : {} to mul.", ins);
-                                    false
-                                }
-                            }
-                            "da" => {
-                                if ins == "A" {
-                                    let mut acc = self.simulator.internal_memory.memory
-                                        [Sim8051::sfr_addr(&self.simulator.accumulator) as usize];
-                                    let psw = self.simulator.internal_memory.memory
-                                        [Sim8051::sfr_addr(&self.simulator.psw) as usize];
-                                    let low_nibble = acc & 0x0F;
-                                    if low_nibble > 9 || (psw & (1 << 6)) > 0 {
-                                        acc = acc + 0x06;
-                                    }
-                                    if ((acc & 0xF0) >> 4) > 9 || (psw & (1 << 7)) > 0 {
-                                        acc = acc + 0x60;
-                                    }
-                                    self.simulator.internal_memory.memory
-                                        [Sim8051::sfr_addr(&self.simulator.accumulator) as usize] =
-                                        acc;
-                                    true
-                                } else {
-                                    println!("Invalid operand to da");
-                                    false
-                                }
-                            }
-                            ins => {
-                                // This not a single operand instruction
-                                println!("Invalid single operand instruction {}.", ins);
-                                false
+            let result = match (mnemonic.as_str(), ops.as_slice()) {
+                ("end", []) => {
+                    self.add_listing(entry);
+                    break;
+                }
+                ("org", [value]) => self
+                    .location_value(value)
+                    .map(|value| addr = value as u32)
+                    .map_err(|msg| AsmError::at(0, msg)),
+                // Reserved bytes are only skipped, they are not cleared
+                // Counts are 16 bit two's complement like the rest of the values, so 8000H and up are negative
+                ("ds", [count]) => match self.location_value(count) {
+                    Err(msg) => Err(AsmError::at(0, msg)),
+                    Ok(value) if value >= 0x8000 => {
+                        Err(AsmError::at(0, format!("DS count {} is negative", count)))
+                    }
+                    Ok(value) => fits_in_code(addr, value as u32)
+                        .map(|_| {
+                            entry.addr = Some(addr as u16);
+                            addr += value as u32;
+                        })
+                        .map_err(AsmError::from),
+                },
+                (directive, _) if lexer::SYMBOL_DIRECTIVES.contains(&directive) => {
+                    match stmt.name {
+                        Some(name) => {
+                            // Only SET symbols can be given a new value
+                            let redefined = self.symbols.get(&name).is_some_and(|sym| {
+                                directive != "set" || sym.kind != SymbolKind::Set
+                            });
+                            if !self.final_pass && redefined {
+                                let msg = format!("symbol {} defined more than once", name);
+                                self.report(
+                                    Severity::Error,
+                                    n + 1,
+                                    text,
+                                    word_span(text, &name),
+                                    msg,
+                                );
                             }
+                            self.define(&name, directive, &ops)
                         }
+                        None => Err(format!("{} needs a symbol name before it", directive).into()),
                     }
-                } else {
-                    println!("No next token found.. -_-");
-                    false
                 }
-            }
-        }
-    }
-
-    fn tstmt(&mut self, command: &str, first: &str) -> bool {
-        // Most of the instructions fall on this category
-        // Retrieve the second operand here
-        // Lets start with basic move instruction which everyone loves
-        let next_token = self.tokenizer.parse_all_as_id();
-
-        let mut success = true;
-
-        if let Some(token) = next_token {
-            if let lexer::TokenType::ID(second) = token.token {
-                if let (Some(op1), Some(op2)) = (
-                    lexer::Tokenizer::parse_all(first),
-                    lexer::Tokenizer::parse_all(&second),
-                ) {
-                    self.tokenizer.pos += token.len;
-                    if self.tokenizer.consume_comma() {
-                        println!("Consumed comma and getting ready");
-                        self.fstmt(command, &first, &second);
-                    } else {
-                        // Now execute the command
-                        use lexer::TokenType::*;
-                        use std::str::FromStr;
-
-                        match command {
-                            "mov" => {
-                                let src = match op1.token {
-                                    HEX(hex) => Some(hex as u8),
-                                    IMM(_) => None,
-                                    ID(reg) => {
-                                        // This is the direct register addressing mode .. it should be a register
-
-                                        // Return its location depending upon the currently selected register bank
-                                        let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                            Sim8051::IRegs::PSW,
-                                        ))
-                                            as usize;
-                                        let count = (0x18
-                                            & self.simulator.internal_memory.memory[pswloc])
-                                            >> 3;
-                                        let start = count * 8;
-
-                                        // try parsing it as a scratchpad register
-                                        // or it could be a port too
-                                        // TODO :: PSW is directly writable from here, so maybe consider changing the behaviour
-                                        let memloc = match Sim8051::ScratchpadRegisters::from_str(
-                                            reg.as_str(),
-                                        ) {
-                                            Ok(reg) => start + reg.reg_count(),
-                                            Err(_) => {
-                                                // before that see it it is A
-                                                if reg == "A" {
-                                                    Sim8051::sfr_addr(&self.simulator.accumulator)
-                                                }
-                                                // else if reg == "SP" {
-                                                //     Sim8051::sfr_addr(&Sim8051::SFR::Reg(Sim8051::IRegs::SP))
-                                                // }
-                                                else {
-                                                    Sim8051::sfr_addr(
-                                                        &Sim8051::SFR::from_str(reg.as_str())
-                                                            .expect(
-                                                            "Not a scratchpad or port or Acc or B",
-                                                        ),
-                                                    )
-                                                }
-                                            }
-                                        };
-
-                                        Some(memloc)
-                                    }
-                                    // For indirect addressing, retrieve the value of the register to use as src location
-                                    IND(reg) => {
-                                        let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                            Sim8051::IRegs::PSW,
-                                        ))
-                                            as usize;
-                                        let count = (0x18
-                                            & self.simulator.internal_memory.memory[pswloc])
-                                            >> 3;
-                                        let val = count * 8 + reg.reg_count();
-                                        Some(self.simulator.internal_memory.memory[val as usize])
-                                    }
-                                    _ => None,
-                                };
-                                let dest = match op2.token {
-                                    HEX(hex) => {
-                                        Some(self.simulator.internal_memory.memory[hex as usize])
-                                    }
-                                    IMM(hex) => Some(hex as u8), // This is the error but can't return anything here .. so changing the return type
-                                    ID(reg) => {
-                                        let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                            Sim8051::IRegs::PSW,
-                                        ))
-                                            as usize;
-                                        let count = (0x18
-                                            & self.simulator.internal_memory.memory[pswloc])
-                                            >> 3;
-                                        let start = count * 8;
-
-                                        let memloc = match Sim8051::ScratchpadRegisters::from_str(
-                                            reg.as_str(),
-                                        ) {
-                                            Ok(reg) => start + reg.reg_count(),
-                                            Err(_) => {
-                                                if reg == "A" {
-                                                    Sim8051::sfr_addr(&self.simulator.accumulator)
-                                                } else {
-                                                    Sim8051::sfr_addr(
-                                                        &Sim8051::SFR::from_str(reg.as_str())
-                                                            .expect(
-                                                            "Not a scratchpad or port or Acc or B",
-                                                        ),
-                                                    )
-                                                }
-                                            }
-                                        };
-                                        Some(self.simulator.internal_memory.memory[memloc as usize])
-                                    }
-                                    // For indirect addressing, retrieve the value of the register to use as src location
-                                    IND(reg) => {
-                                        let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                            Sim8051::IRegs::PSW,
-                                        ))
-                                            as usize;
-                                        let count = (0x18
-                                            & self.simulator.internal_memory.memory[pswloc])
-                                            >> 3;
-                                        let val = count * 8 + reg.reg_count();
-                                        Some(
-                                            self.simulator.internal_memory.memory[self
-                                                .simulator
-                                                .internal_memory
-                                                .memory
-                                                [val as usize]
-                                                as usize],
-                                        )
-                                    }
-                                    _ => None,
-                                };
-                                println!("Moved from {} to {}", src.unwrap(), dest.unwrap());
-                                self.simulator.mov(src.unwrap(), dest.unwrap());
-                                success = true;
-                            }
-                            // where's the single binding?
-                            inst @ "add" | inst @ "addc" | inst @ "subb" => {
-                                // First operand is always A .. makes one thing easy
-                                // lol .. need to update flags accordingly now
-                                // There are four main flags that need to be set :
-                                // C, AC, OV and P :- Getting boooorring now
-                                // TODO :: Correct AC flag for subb instruction .. Who would care for that though .. but still
-                                if first == "A" {
-                                    let operand =
-                                        lexer::retrieve_rvalue(&mut self.simulator, &op2.token);
-                                    // Allow addition with wraparound effect
-                                    let addr = Sim8051::sfr_addr(&self.simulator.accumulator);
-                                    let mut val =
-                                        self.simulator.internal_memory.memory[addr as usize] as i32;
-                                    let mut should_set_carry = false;
-                                    let mut factor = 1;
-                                    if inst == "addc" || inst == "subb" {
-                                        // check the carry flag
-                                        // special case if 0xff is A
-                                        if inst == "subb" {
-                                            factor = -1;
-                                        }
-                                        let psw = Sim8051::sfr_addr(&self.simulator.psw);
-                                        if (psw & 0x80) > 0 {
-                                            val += factor;
-                                            if val & 0xff00 > 1 {
-                                                should_set_carry = true;
-                                            }
-                                            val = val & 0xff;
-                                        }
-                                    }
-
-                                    let temp = val;
-                                    val = val + factor * operand.unwrap() as i32;
-                                    let ans = (val & 0xff) as u8;
-                                    self.simulator.internal_memory.memory[addr as usize] = ans;
-                                    // setting these flags is plain pain
-                                    use std::ops::BitOr;
-                                    should_set_carry =
-                                        should_set_carry.bitor((val as i32 & 0xff00) > 0);
-                                    self.simulator.set_carry_bit(should_set_carry);
-                                    self.simulator.set_parity_bit(ans);
-
-                                    let should_set_parity =
-                                        ((temp & 0x000F + operand.unwrap() as i32 & 0x000F) >> 4)
-                                            > 0;
-                                    self.simulator.set_auxiliary_carry_bit(should_set_parity);
-
-                                    // lastly overflow bit
-                                    // overflow bit is set when there's overflow from 7th bit or 8th bit but not from both
-                                    let is_carry_to_msb =
-                                        ((temp & 0x007F) + (operand.unwrap() as i32 & 0x007F) >> 7)
-                                            > 0;
-                                    use std::ops::BitXor;
-                                    self.simulator.set_auxiliary_carry_bit(
-                                        should_set_carry.bitxor(is_carry_to_msb),
-                                    );
-                                } else {
-                                    println!("Invalid operand to {} instruction ", inst);
-                                    success = false;
-                                }
-                            }
-                            // "subb" => { // I guess subb can be merged with addc and add instructions // merged above
-                            // Implementing these bitwise operations instruction is a different kind of pain
-                            // They have 8 addressing modes .. -_- -_- -_-
-                            "anl" => anl_orl_xrl(self, command, first, &second, |x, y| {
-                                use std::ops::BitAnd;
-                                x.bitand(y)
-                            }),
-                            "orl" => anl_orl_xrl(self, command, first, &second, |x, y| {
-                                use std::ops::BitOr;
-                                x.bitor(y)
-                            }),
-                            "xrl" => anl_orl_xrl(self, command, first, &second, |x, y| {
-                                use std::ops::BitXor;
-                                x.bitxor(y)
-                            }),
-
-                            "jb" | "jnb" => {
-                                // Either in a bit addressable location or bit addressable register
-                                if let Some(token) = lexer::Tokenizer::parse_all(first) {
-                                    use lexer::TokenType::*;
-                                    let condition = match token.token {
-                                        HEX(val) => self
-                                            .simulator
-                                            .internal_memory
-                                            .get_bit_status_addr_memory(val as u8),
-                                        BIT_ADDR(reg, bit) => {
-                                            // Retrieve operand manually
-                                            (self.simulator.internal_memory.memory
-                                                [Sim8051::sfr_addr(&reg) as usize]
-                                                & (1 << bit))
-                                                > 0
-                                        }
-                                        _ => {
-                                            panic!(
-                                            "Panicking once again for not being bit addressable"
-                                        );
-                                        }
-                                    };
-                                    // parse the label
-                                    let pos = self.jmptable.get(&second);
-                                    if let Some(&val) = &pos {
-                                        if condition && command == "jb" {
-                                            self.tokenizer.pos = val;
-                                        } else if !condition && command == "jnb" {
-                                            self.tokenizer.pos = val;
-                                        }
-                                    } else {
-                                        success = false;
-                                        panic!("Not a valid jmp label {}", second);
-                                    }
-                                } else {
-                                    println!("Not a bit addressable value panic");
-                                    success = false;
-                                }
-                            }
-                            "djnz" => {
-                                // what does this command do ?
-                                // -> Decrease byte at given address and jmp to label if it is not zero
-                                // only allows direct and register addressing mode
-                                // Doesn't know what keil does for direct addressing/register addressing .. if it allows Port address or not .. NO keil installed
-                                if let Some(to) = lexer::Tokenizer::parse_all(first) {
-                                    use lexer::TokenType::*;
-                                    let addr = match to.token {
-                                        HEX(hex) => Some(hex as u8),
-                                        ID(id) => {
-                                            // parse as sctrachpad register
-                                            // locate current register bank first
-                                            let reg = Sim8051::ScratchpadRegisters::from_str(&id)
-                                                .expect("Not a scratchpad register error ...");
-                                            let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                                Sim8051::IRegs::PSW,
-                                            ))
-                                                as usize;
-                                            let count: u8 = (0x18
-                                                & self.simulator.internal_memory.memory[pswloc])
-                                                >> 3;
-                                            Some(count * 8 + reg.reg_count())
-                                        }
-                                        _ => {
-                                            println!("Invalid addressing mode to djnz : {}", first);
-                                            success = false;
-                                            None
-                                        }
-                                    };
-                                    // decrease the value at that location by 1 using wraparound arithmetic
-                                    let mut refval = &mut self.simulator.internal_memory.memory
-                                        [addr.unwrap() as usize];
-                                    *refval = ((*refval as i16 - 1) & 0x00FF) as u8;
-                                    // Now jump if it needs to
-                                    let pos = self.jmptable.get(&second);
-                                    if let Some(&val) = &pos {
-                                        self.tokenizer.pos = val;
-                                    } else {
-                                        success = false;
-                                        panic!("Not a valid jmp label {}", second);
-                                    }
-                                } else {
-                                    println!("Invalid first operand to djnz : {}", first);
-                                    success = false
-                                }
-                            }
-                            "xch" => {
-                                //Exchange contents of accumulator and given address/register
-                                if first != "A" {
-                                    println!("Invalid first operand to xch : {}", first);
-                                    success = false;
-                                } else {
-                                    if let Some(tok) = lexer::Tokenizer::parse_all(&second) {
-                                        use lexer::TokenType::*;
-                                        let addr = match tok.token {
-                                            HEX(hex) => Some(hex as u8),
-                                            ID(reg) => {
-                                                let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                                    Sim8051::IRegs::PSW,
-                                                ))
-                                                    as usize;
-                                                let count = (0x18
-                                                    & self.simulator.internal_memory.memory
-                                                        [pswloc])
-                                                    >> 3;
-                                                let start = count * 8;
-                                                let memloc =
-                                                    match Sim8051::ScratchpadRegisters::from_str(
-                                                        reg.as_str(),
-                                                    ) {
-                                                        Ok(reg) => start + reg.reg_count(),
-                                                        Err(_) => {
-                                                            if reg == "A" {
-                                                                Sim8051::sfr_addr(
-                                                                    &self.simulator.accumulator,
-                                                                )
-                                                            } else {
-                                                                Sim8051::sfr_addr(
-                                                                &Sim8051::SFR::from_str(reg.as_str())
-                                                                    .expect(
-                                                                        "Not a scratchpad or port or Acc or B",
-                                                        ),
-                                                    )
-                                                            }
-                                                        }
-                                                    };
-
-                                                Some(memloc)
-                                            }
-                                            // For indirect addressing, retrieve the value of the register to use as src location
-                                            IND(reg) => {
-                                                let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(
-                                                    Sim8051::IRegs::PSW,
-                                                ))
-                                                    as usize;
-                                                let count = (0x18
-                                                    & self.simulator.internal_memory.memory
-                                                        [pswloc])
-                                                    >> 3;
-                                                let val = count * 8 + reg.reg_count();
-                                                Some(
-                                                    self.simulator.internal_memory.memory
-                                                        [val as usize],
-                                                )
-                                            }
-                                            _ => None,
-                                        };
-                                        // swap the content
-                                        let op1 = self.simulator.internal_memory.memory
-                                            [Sim8051::sfr_addr(&self.simulator.accumulator)
-                                                as usize];
-                                        let op2 = self.simulator.internal_memory.memory
-                                            [addr.unwrap() as usize];
-                                        // swapping in a fancy style
-
-                                        // fk this borrow thing, my beautiful solution not working
-                                        // *op1ref = *op1ref + *op2ref;
-                                        // *op2ref = *op1ref;
-                                        // *op1ref = *op1ref - *op2ref;
-                                        self.simulator.internal_memory.memory
-                                            [addr.unwrap() as usize] = op1;
-                                        self.simulator.internal_memory.memory[Sim8051::sfr_addr(
-                                            &self.simulator.accumulator,
-                                        )
-                                            as usize] = op2;
-                                        success = true;
-                                    } else {
-                                        success = false;
-                                        println!("Invalid second operand to xch : {}", second);
-                                    }
-                                }
-                            }
-                            _ => success = false,
+                _ => {
+                    let bytes = match mnemonic.as_str() {
+                        "db" | "dw" => self.define_bytes(&mnemonic, &ops),
+                        _ => self.encode(&mnemonic, &stmt.operands, addr as u16),
+                    };
+                    bytes.and_then(|bytes| {
+                        fits_in_code(addr, bytes.len() as u32)?;
+                        if self.final_pass {
+                            let start = addr as usize;
+                            self.simulator.code_memory[start..start + bytes.len()]
+                                .copy_from_slice(&bytes);
                         }
+                        entry.addr = Some(addr as u16);
+                        addr += bytes.len() as u32;
+                        entry.bytes = bytes;
+                        Ok(())
+                    })
+                }
+            };
+
+            if !self.final_pass {
+                self.first_pass_ends.insert(n, addr);
+            }
+            if let Err(err) = result {
+                // Anything failing in the first pass fails again in the second one, where it is reported
+                // Points at the operand the error is about if there is one
+                if self.final_pass {
+                    // Whatever failed only now still takes the room it had in the first pass,
+                    // so the labels after it don't all come out as phase errors
+                    if let Some(end) = self.first_pass_ends.get(&n) {
+                        addr = *end;
                     }
+                    let span = err
+                        .operand
+                        .and_then(|index| operand_span(text, &ops, index))
+                        .unwrap_or_else(|| statement_span(text));
+                    self.report(Severity::Error, n + 1, text, span, err.msg.clone());
+                    entry.error = Some(err.msg);
                 }
             }
+            self.add_listing(entry);
+        }
+        if self.final_pass {
+            self.end_address = addr;
         }
-        success
     }
 
-    // fourth statement procedure and  will handle instructions wth 3 operands
-    fn fstmt(&mut self, command: &str, first: &str, second: &str) {
-        let new_token = self.tokenizer.parse_all_as_id();
-        let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(Sim8051::IRegs::PSW)) as usize;
-        let count: u8 = (0x18 & self.simulator.internal_memory.memory[pswloc]) >> 3;
-        // lol.. can't use logical and with if let
-        if let Some(tok) = new_token {
-            // If I were to rewrite it, the addressing mode thing could have been done much more nicely
-            if command == "cjne" {
-                // wtf .. why cjne had to set carry flag .. didn't they find any easier way for conditional branching
-                // parse the first argument
-                // Its either A, Rn or @Rn
-                if let (Some(op1), Some(op2)) = (
-                    lexer::Tokenizer::parse_all(&first),
-                    lexer::Tokenizer::parse_all(&second),
-                ) {
-                    use lexer::TokenType::*;
-                    use std::str::FromStr;
-                    let mut involve_acc = false;
-                    let src_op = match op1.token {
-                        IND(reg) => {
-                            // Retrieve the active memory bank
-                            Some(
-                                self.simulator.internal_memory.memory
-                                    [(count * 8 + reg.reg_count()) as usize],
-                            )
-                        }
-                        ID(id) => {
-                            if id == "A" {
-                                // yo toriley feri direct addressing linxa
-                                involve_acc = true;
-                                Some(Sim8051::sfr_addr(&self.simulator.accumulator))
-                            } else {
-                                match Sim8051::ScratchpadRegisters::from_str(id.as_str()) {
-                                    Ok(T) => Some((count * 8 + T.reg_count()) as u8),
-                                    Err(_) => None,
-                                }
-                            }
-                        }
-                        _ => None,
-                    };
-                    let dest_val = match op2.token {
-                        IMM(hex) => Some(hex as u8),
-                        ID(id) => {
-                            if involve_acc {
-                                match Sim8051::ScratchpadRegisters::from_str(id.as_str()) {
-                                    Ok(T) => Some(
-                                        self.simulator.internal_memory.memory
-                                            [(count * 8 + T.reg_count()) as usize],
-                                    ),
-                                    Err(_) => None,
-                                }
-                            } else {
-                                None
-                            }
-                        }
-                        _ => None,
-                    };
-                    // stupid instructon
-                    let src_val = self.simulator.internal_memory.memory[src_op.unwrap() as usize];
-                    let psw_loc = Sim8051::sfr_addr(&self.simulator.psw) as usize;
-                    let refpsw = &mut self.simulator.internal_memory.memory[psw_loc];
-                    if src_val < dest_val.unwrap() {
-                        // set the carry flag or reset if lmao
-                        *refpsw = *refpsw | 0x80;
-                    } else {
-                        *refpsw &= 0x7F;
-                    }
-                    // Prepare for long jump .. get set go
-                    if let lexer::TokenType::ID(id) = tok.token {
-                        let jmp_pos = self.jmptable.get(&id);
-                        if let Some(&val) = jmp_pos {
-                            self.tokenizer.pos = val;
-                        } else {
-                            println!("Invalid jmp attempted to label {}", id);
-                        }
-                    }
-                } else {
-                    println!("Invalid operands {} and {} to cjne", first, second);
+    fn report(
+        &mut self,
+        severity: Severity,
+        line: usize,
+        text: &str,
+        span: Range<usize>,
+        msg: String,
+    ) {
+        let file = if self.file.is_empty() {
+            "source"
+        } else {
+            &self.file
+        };
+        let diagnostic = Diagnostic::new(severity, file, line, text, span, msg);
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn add_listing(&mut self, entry: ListingLine) {
+        if self.final_pass {
+            self.listing.push(entry);
+        }
+    }
+
+    // name EQU value and the like .. the second pass defines them again, which picks up forward references
+    fn define(&mut self, name: &str, directive: &str, ops: &[&str]) -> Result<(), AsmError> {
+        let op = match ops {
+            [op] => *op,
+            _ => return Err(format!("{} takes a single value", directive).into()),
+        };
+        let sym = match directive {
+            "bit" => self
+                .bit_addr(op)
+                .map(|value| (value as u16, SymbolKind::Bit)),
+            "data" => self
+                .direct_addr(op)
+                .map(|value| (value as u16, SymbolKind::Data)),
+            "idata" => self
+                .byte_value(op)
+                .map(|value| (value as u16, SymbolKind::Idata)),
+            "xdata" => self.symbol(op).map(|x| (x.value, SymbolKind::Xdata)),
+            "code" => self.symbol(op).map(|x| (x.value, SymbolKind::Code)),
+            "set" => self.symbol(op).map(|x| (x.value, SymbolKind::Set)),
+            // EQU of another symbol keeps its kind, so that DATA and BIT symbols can be aliased
+            _ => self.symbol(op).map(|x| match x.kind {
+                SymbolKind::Set => (x.value, SymbolKind::Number),
+                kind => (x.value, kind),
+            }),
+        };
+        let (value, kind) = sym.ok_or_else(|| self.operand_error(directive, ops))?;
+        self.symbols
+            .insert(name.to_string(), Symbol { value, kind });
+        self.defined.insert(name.to_string());
+        Ok(())
+    }
+
+    // DB takes bytes and strings, DW takes words which are stored high byte first like every other 16 bit value
+    fn define_bytes(&self, directive: &str, ops: &[&str]) -> Result<Vec<u8>, AsmError> {
+        if ops.is_empty() {
+            return Err(format!("{} needs at least one value", directive).into());
+        }
+        let mut bytes = Vec::new();
+        for (i, op) in ops.iter().enumerate() {
+            if let Some(text) = string_literal(op) {
+                if directive == "dw" {
+                    return Err(AsmError::at(
+                        i,
+                        String::from("Strings are only allowed in DB"),
+                    ));
                 }
+                bytes.extend_from_slice(text.as_bytes());
+                continue;
+            }
+            if directive == "db" {
+                bytes.push(
+                    self.byte_value(op)
+                        .ok_or_else(|| self.operand_error(directive, ops))?,
+                );
             } else {
-                println!("Invalid three argument command {}", command);
+                let word = self.value(op).map_err(|msg| AsmError::at(i, msg))?;
+                bytes.extend_from_slice(&word.to_be_bytes());
             }
-        } else {
-            println!("Invald token at line {}", self.tokenizer.pos);
+        }
+        Ok(bytes)
+    }
+
+    // Source line is turned into an Instruction first, which then knows its own size and encoding
+    // Forward references are still 0 in the first pass, so only the size is taken from there
+    fn encode(&self, mnemonic: &str, operands: &[String], addr: u16) -> Result<Vec<u8>, AsmError> {
+        let instruction = self.instruction(mnemonic, operands)?;
+        if !self.final_pass {
+            return Ok(vec![0x00; instruction.size() as usize]);
+        }
+        // Only the jump ranges can fail here, and the target is always the last operand
+        instruction
+            .encode(addr)
+            .map_err(|msg| match operands.last() {
+                Some(label) => AsmError::at(operands.len() - 1, format!("{} : {}", label, msg)),
+                None => msg.into(),
+            })
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[String]) -> Result<Instruction, AsmError> {
+        use Instruction::*;
+        use Operand::{Dptr, C};
+        use Slot::*;
+        let ops: Vec<&str> = operands.iter().map(|x| x.as_str()).collect();
+        let invalid = || self.operand_error(mnemonic, &ops);
+        // Jump targets always come last
+        let target = |op: &str| {
+            self.target(op)
+                .map_err(|msg| AsmError::at(ops.len() - 1, msg))
+        };
+        let op = |op: &str, slot: Slot| match slot {
+            Relative => target(op).map(Operand::Relative),
+            Absolute => target(op).map(Operand::Absolute),
+            Long => target(op).map(Operand::Long),
+            _ => self.operand(op, slot).ok_or_else(invalid),
+        };
+
+        let instruction = match (mnemonic, ops.as_slice()) {
+            ("nop", []) => Nop,
+            ("ret", []) => Ret,
+            ("reti", []) => Reti,
+
+            ("mov", ["DPTR", src]) => Mov(Dptr, op(src, Wide)?),
+            ("mov", ["C", bit]) => Mov(C, op(bit, Bit)?),
+            ("mov", [bit, "C"]) => Mov(op(bit, Bit)?, C),
+            ("mov", [dst, src]) => Mov(op(dst, Data)?, op(src, Data)?),
+            ("movc", ["A", src]) => Movc(op(src, Data)?),
+            ("movx", [dst, src]) => Movx(op(dst, Data)?, op(src, Data)?),
+
+            ("add", ["A", src]) => Add(op(src, Data)?),
+            ("addc", ["A", src]) => Addc(op(src, Data)?),
+            ("subb", ["A", src]) => Subb(op(src, Data)?),
+            ("inc", [dst]) => Inc(op(dst, Data)?),
+            ("dec", [dst]) => Dec(op(dst, Data)?),
+            ("mul", ["AB"]) => Mul,
+            ("div", ["AB"]) => Div,
+            ("da", ["A"]) => Da,
+
+            // ANL C, /bit reads the complement of the bit
+            ("anl", ["C", bit]) => Anl(C, op(bit, Bit)?),
+            ("orl", ["C", bit]) => Orl(C, op(bit, Bit)?),
+            ("anl", [dst, src]) => Anl(op(dst, Data)?, op(src, Data)?),
+            ("orl", [dst, src]) => Orl(op(dst, Data)?, op(src, Data)?),
+            ("xrl", [dst, src]) => Xrl(op(dst, Data)?, op(src, Data)?),
+            ("swap", ["A"]) => Swap,
+            ("rr", ["A"]) => Rr,
+            ("rrc", ["A"]) => Rrc,
+            ("rl", ["A"]) => Rl,
+            ("rlc", ["A"]) => Rlc,
+            ("clr", [dst]) => Clr(op(dst, Bit)?),
+            ("setb", [dst]) => Setb(op(dst, Bit)?),
+            ("cpl", [dst]) => Cpl(op(dst, Bit)?),
+
+            ("jc", [label]) => Jc(op(label, Relative)?),
+            ("jnc", [label]) => Jnc(op(label, Relative)?),
+            ("jz", [label]) => Jz(op(label, Relative)?),
+            ("jnz", [label]) => Jnz(op(label, Relative)?),
+            ("jbc", [bit, label]) => Jbc(op(bit, Bit)?, op(label, Relative)?),
+            ("jb", [bit, label]) => Jb(op(bit, Bit)?, op(label, Relative)?),
+            ("jnb", [bit, label]) => Jnb(op(bit, Bit)?, op(label, Relative)?),
+            ("djnz", [dst, label]) => Djnz(op(dst, Data)?, op(label, Relative)?),
+            ("cjne", [first, second, label]) => {
+                Cjne(op(first, Data)?, op(second, Data)?, op(label, Relative)?)
+            }
+            ("sjmp", [label]) => Sjmp(op(label, Relative)?),
+            ("jmp", ["@A+DPTR"]) => Jmp,
+            ("ajmp", [label]) => Ajmp(op(label, Absolute)?),
+            ("acall", [label]) => Acall(op(label, Absolute)?),
+            // Generic JMP and CALL always take the long form, so that their size is known in the first pass
+            ("ljmp" | "jmp", [label]) => Ljmp(op(label, Long)?),
+            ("lcall" | "call", [label]) => Lcall(op(label, Long)?),
+
+            // Accumulator can only be pushed through its direct address
+            ("push", ["A"]) => Push(Operand::Direct(Self::acc_addr())),
+            ("pop", ["A"]) => Pop(Operand::Direct(Self::acc_addr())),
+            ("push", [src]) => Push(op(src, Data)?),
+            ("pop", [dst]) => Pop(op(dst, Data)?),
+            ("xch", ["A", src]) => Xch(op(src, Data)?),
+            ("xchd", ["A", src]) => Xchd(op(src, Data)?),
+
+            _ => return Err(format!("Unknown instruction {} {}", mnemonic, ops.join(", ")).into()),
+        };
+        if !instruction.is_valid() {
+            return Err(invalid());
+        }
+        Ok(instruction)
+    }
+
+    fn acc_addr() -> u8 {
+        Sim8051::sfr_addr(&Sim8051::SFR::Reg(Sim8051::IRegs::ACC))
+    }
+
+    // Resolves the address a jump refers to .. a code symbol, a plain address or an expression like $+2
+    // Labels not seen yet during the first pass don't matter as only the sizes are needed there
+    fn target(&self, op: &str) -> Result<u16, String> {
+        match self.evaluate(op)? {
+            Symbol {
+                value,
+                kind: SymbolKind::Code | SymbolKind::Number | SymbolKind::Set,
+            } => Ok(value),
+            _ => Err(format!("Invalid jump target {}", op)),
         }
     }
 }
 
-fn clr_set_cpl(
-    asm: &mut Assembler,
-    ins: &str,
-    operand: &str,
-    operator: fn(&mut InternalMemory, u8, u8),
-) -> bool {
-    if (ins == "cpl") || (ins == "clr") {
-        if operand == "A" {
-            // Clear the contents of the accumulator
-            let loc = Sim8051::sfr_addr(&asm.simulator.accumulator);
-            for i in 0..8 {
-                asm.simulator
-                    .internal_memory
-                    .operate_bit_addressable_memory(loc + i, operator);
-                return true;
+// How a plain value is taken, which depends on where the operand appears
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Data,     // direct address
+    Bit,      // bit address
+    Wide,     // 16 bit immediate data of MOV DPTR
+    Relative, // jump targets of the three sizes
+    Absolute,
+    Long,
+}
+
+// Operands are classified here .. symbols from the symbol table can stand in for addresses and data
+impl Assembler {
+    fn operand(&self, op: &str, slot: Slot) -> Option<Operand> {
+        let operand = match op {
+            "A" => Operand::A,
+            "AB" => Operand::AB,
+            "C" => Operand::C,
+            "DPTR" => Operand::Dptr,
+            "@DPTR" => Operand::IndirectDptr,
+            "@A+DPTR" => Operand::IndirectAccDptr,
+            "@A+PC" => Operand::IndirectAccPc,
+            _ if op.starts_with('@') => Operand::Indirect(indirect(op)?),
+            _ if op.starts_with('#') && slot == Slot::Wide => {
+                Operand::Immediate16(self.immediate16(op)?)
             }
+            _ if op.starts_with('#') => Operand::Immediate(self.immediate(op)?),
+            _ if op.starts_with('/') => Operand::NotBit(self.bit_addr(&op[1..])?),
+            _ => match (register(op), slot) {
+                (Some(n), _) => Operand::Register(n),
+                (None, Slot::Bit) => Operand::Bit(self.bit_addr(op)?),
+                (None, _) => Operand::Direct(self.direct_addr(op)?),
+            },
+        };
+        Some(operand)
+    }
+
+    // Literal, symbol or a whole expression of them .. a lone symbol keeps its kind, anything computed is a number
+    // Undefined symbols are taken as 0 in the first pass
+    fn evaluate(&self, op: &str) -> Result<Symbol, String> {
+        if let Some(sym) = identifier(op).and_then(|name| self.lookup(&name)) {
+            return Ok(sym);
         }
+        let lookup = |name: &str| match self.lookup(name) {
+            Some(sym) => Ok(sym.value),
+            None if !self.final_pass => Ok(0x0000),
+            None if self.symbols.contains_key(name) => Err(format!(
+                "Forward reference to {}, ORG and DS only take symbols defined above them",
+                name
+            )),
+            None => Err(format!("Undefined symbol {}", name)),
+        };
+        expr::evaluate(op, self.location, &lookup).map(|value| Symbol {
+            value,
+            kind: SymbolKind::Number,
+        })
     }
-    match operand {
-        "C" => {
-            // Reset the carry flag
-            let psw = Sim8051::SFR::Reg(Sim8051::IRegs::PSW);
-            asm.simulator
-                .internal_memory
-                .operate_bit_addressable_registers(Sim8051::sfr_addr(&psw), 7, operator);
-            true
+
+    // Symbols of the source first, then the SFRs and SFR bits every 8051 assembler predefines
+    fn lookup(&self, name: &str) -> Option<Symbol> {
+        use std::str::FromStr;
+        if let Some(sym) = self.symbols.get(name) {
+            if self.backward_only && !self.defined.contains(name) {
+                return None;
+            }
+            return Some(*sym);
         }
-        // It also support resetting of bit addressable memory
-        rstr => {
-            if let Some(hex) = lexer::Tokenizer::parse_hex(rstr) {
-                asm.simulator
-                    .internal_memory
-                    .operate_bit_addressable_memory(hex as u8, operator);
-                true
-            } else {
-                // Try to parse it as bit addressable registers
-                if let Some(bitaddr) = Tokenizer::parse_bitaddr(rstr) {
-                    match bitaddr.token {
-                        lexer::TokenType::BIT_ADDR(sfr, bit) => {
-                            asm.simulator
-                                .internal_memory
-                                .operate_bit_addressable_registers(
-                                    Sim8051::sfr_addr(&sfr),
-                                    bit,
-                                    operator,
-                                );
-                            true
-                        }
-                        _ => false,
-                    }
-                } else {
-                    println!("Error not a bit address");
-                    false
-                }
+        if let Ok(sfr) = Sim8051::SFR::from_str(name) {
+            return Some(Symbol {
+                value: Sim8051::sfr_addr(&sfr) as u16,
+                kind: SymbolKind::Data,
+            });
+        }
+        Sim8051::named_bit(name).map(|bit| Symbol {
+            value: bit as u16,
+            kind: SymbolKind::Bit,
+        })
+    }
+
+    fn symbol(&self, op: &str) -> Option<Symbol> {
+        self.evaluate(op).ok()
+    }
+
+    fn value(&self, op: &str) -> Result<u16, String> {
+        self.evaluate(op).map(|x| x.value)
+    }
+
+    // Value of ORG and DS, which decide where everything below goes
+    fn location_value(&mut self, op: &str) -> Result<u16, String> {
+        self.backward_only = true;
+        let value = self.value(op);
+        self.backward_only = false;
+        value
+    }
+
+    // 8 bit data, negative values down to -128 are taken in two's complement
+    fn byte_value(&self, op: &str) -> Option<u8> {
+        self.symbol(op)
+            .filter(|x| fits_byte(x.value))
+            .map(|x| x.value as u8)
+    }
+
+    fn immediate(&self, op: &str) -> Option<u8> {
+        op.strip_prefix('#').and_then(|x| self.byte_value(x.trim()))
+    }
+
+    fn immediate16(&self, op: &str) -> Option<u16> {
+        op.strip_prefix('#')
+            .and_then(|x| self.symbol(x.trim()))
+            .map(|x| x.value)
+    }
+
+    // SFR names come through the predefined symbols
+    fn direct_addr(&self, op: &str) -> Option<u8> {
+        match self.symbol(op)? {
+            Symbol {
+                value,
+                kind: SymbolKind::Data | SymbolKind::Idata | SymbolKind::Number | SymbolKind::Set,
+            } if value <= 0xFF => Some(value as u8),
+            _ => None,
+        }
+    }
+
+    fn bit_addr(&self, op: &str) -> Option<u8> {
+        if let Some(bit) = self.dotted_bit(op) {
+            return bit.ok();
+        }
+        match self.symbol(op)? {
+            Symbol {
+                value,
+                kind: SymbolKind::Bit | SymbolKind::Number | SymbolKind::Set,
+            } if value <= 0xFF => Some(value as u8),
+            _ => None,
+        }
+    }
+
+    // byte.n with the byte given as a bit addressable SFR, an address or a DATA symbol .. ACC.7, 20H.3 or FLAGS.0
+    // None when the operand isn't written that way at all
+    fn dotted_bit(&self, op: &str) -> Option<Result<u8, String>> {
+        if let Some(token) = Tokenizer::parse_all(op).filter(|x| x.len == op.len()) {
+            if let lexer::TokenType::BIT_ADDR(bit) = token.token {
+                return Some(Ok(bit));
+            }
+        }
+        let (base, bit) = op.rsplit_once('.')?;
+        let bit = match bit.trim().parse::<u8>() {
+            Ok(bit) if bit < 8 => bit,
+            _ => return Some(Err(format!("Bit number of {} should be 0 to 7", op))),
+        };
+        let byte = match self.evaluate(base.trim()) {
+            Ok(Symbol {
+                value,
+                kind: SymbolKind::Data | SymbolKind::Idata | SymbolKind::Number | SymbolKind::Set,
+            }) if value <= 0xFF => value as u8,
+            Ok(_) => {
+                return Some(Err(format!(
+                    "{} is not a bit, {} is not a byte address",
+                    op, base
+                )))
             }
+            Err(msg) => return Some(Err(msg)),
+        };
+        // Forward references are still 0 in the first pass
+        match Sim8051::bit_address(byte, bit) {
+            Some(bit) => Some(Ok(bit)),
+            None if !self.final_pass => Some(Ok(0x00)),
+            None => Some(Err(format!(
+                "{} is not a bit, {} is not bit addressable",
+                op, base
+            ))),
         }
     }
-}
 
-fn anl_orl_xrl(
-    asm: &mut Assembler,
-    ins: &str,
-    op1: &str,
-    op2: &str,
-    operator: fn(bool, bool) -> bool,
-) {
-    // let's go 8 addressing modes.. tf
-    // first with the single operations on the carry bit
-    match op1 {
-        "C" => {
-            // There's this stupid syntax that came from nowhere just for these logical instructions
-            // ORL C, /22h
-            // pattern matching not working.. not strong as Haskell's
-            let pswloc = Sim8051::sfr_addr(&asm.simulator.psw);
-            if op2.starts_with("/") {
-                // parse remaining string as simple hex
-                if let Some(hex) = lexer::Tokenizer::parse_hex(&op2[1..]) {
-                    // Retrieve bitwise value at that bit addressable location .. .jhyau
-                    let operand = !asm
-                        .simulator
-                        .internal_memory
-                        .get_bit_status_addr_memory(hex as u8);
-
-                    let result = operator(operand, (pswloc & 0x80) > 0);
-                    if result {
-                        asm.simulator.internal_memory.memory[pswloc as usize] |= 0x80;
-                    } else {
-                        asm.simulator.internal_memory.memory[pswloc as usize] &= 0x7F;
-                    }
-                } else {
-                    panic!("Look who is giving garbage after {} instruction", ins);
+    // Points at the operand that fails to evaluate or is out of range if there is one, as that is the more likely mistake
+    fn operand_error(&self, mnemonic: &str, ops: &[&str]) -> AsmError {
+        use std::str::FromStr;
+        // Only MOV DPTR takes 16 bit data
+        let wide = ops.first() == Some(&"DPTR");
+        for (i, op) in ops.iter().enumerate() {
+            let data = op.starts_with('#') || mnemonic == "db";
+            let op = op.trim_start_matches(['#', '/']).trim();
+            let reserved = ["A", "C", "AB", "DPTR", "PC"].contains(&op)
+                || register(op).is_some()
+                || Sim8051::SFR::from_str(op).is_ok()
+                || Sim8051::named_bit(op).is_some();
+            let bit = match self.dotted_bit(op) {
+                _ if data => false,
+                Some(Err(msg)) => return AsmError::at(i, msg),
+                bit => bit.is_some(),
+            };
+            let string = mnemonic == "db" && string_literal(op).is_some();
+            if reserved || bit || string || op.starts_with('@') {
+                continue;
+            }
+            match self.evaluate(op) {
+                Err(msg) => return AsmError::at(i, msg),
+                Ok(sym) if data && !wide && !fits_byte(sym.value) => {
+                    return AsmError::at(i, format!("Value {} does not fit in 8 bits", op))
                 }
-            } else {
-                // TODO :: Reduce these repeated codes simply using Option<T>
-                if let Some(token) = lexer::Tokenizer::parse_all(op2) {
-                    use lexer::TokenType::*;
-                    match token.token {
-                        HEX(val) => {
-                            let operand = asm
-                                .simulator
-                                .internal_memory
-                                .get_bit_status_addr_memory(val as u8);
-                            // Gonna copy paste
-                            let result = operator(operand, (pswloc & 0x80) > 0);
-                            if result {
-                                asm.simulator.internal_memory.memory[pswloc as usize] |= 0x80;
-                            } else {
-                                asm.simulator.internal_memory.memory[pswloc as usize] &= 0x7F;
-                            }
-                        }
-                        BIT_ADDR(reg, bit) => {
-                            // Retrieve operand manually
-                            let operand = (asm.simulator.internal_memory.memory
-                                [Sim8051::sfr_addr(&reg) as usize]
-                                & (1 << bit))
-                                > 0;
-                            let result = operator(operand, (pswloc & 0x80) > 0);
-                            if result {
-                                asm.simulator.internal_memory.memory[pswloc as usize] |= 0x80;
-                            } else {
-                                asm.simulator.internal_memory.memory[pswloc as usize] &= 0x7F;
-                            }
-                        }
-                        _ => {
-                            panic!("Panicking once again")
-                        }
-                    }
-                } else {
-                    panic!("Invalid token to {}", op2);
+                Ok(sym) if !data && sym.kind != SymbolKind::Code && sym.value > 0xFF => {
+                    return AsmError::at(
+                        i,
+                        format!(
+                            "Address {} is out of range, only 00H to FFH can be used",
+                            op
+                        ),
+                    )
                 }
+                _ => {}
             }
         }
-        // then on accumulator
-        "A" => {
-            // Takes every addressing mode .. so copy paste
-            if let Some(op2token) = lexer::Tokenizer::parse_all(op2) {
-                use lexer::TokenType::*;
-                let val = match op2token.token {
-                    HEX(hex) => Some(asm.simulator.internal_memory.memory[hex as usize]),
-                    IMM(hex) => Some(hex as u8), // This is the error but can't return anything here .. so changing the return type
-                    ID(reg) => {
-                        let pswloc =
-                            Sim8051::sfr_addr(&Sim8051::SFR::Reg(Sim8051::IRegs::PSW)) as usize;
-                        let count = (0x18 & asm.simulator.internal_memory.memory[pswloc]) >> 3;
-                        let start = count * 8;
-
-                        use std::str::FromStr;
-                        let memloc = match Sim8051::ScratchpadRegisters::from_str(reg.as_str()) {
-                            Ok(reg) => start + reg.reg_count(),
-                            Err(_) => {
-                                // try parsing as port address now
-                                let port = Sim8051::sfr_addr(
-                                    &Sim8051::SFR::from_str(reg.as_str())
-                                        .expect("Not a scratchpad or port or Acc or B"),
-                                );
-                                port
-                            }
-                        };
-                        Some(asm.simulator.internal_memory.memory[memloc as usize])
-                    }
-                    // For indirect addressing, retrieve the value of the register to use as src location
-                    IND(reg) => {
-                        let pswloc =
-                            Sim8051::sfr_addr(&Sim8051::SFR::Reg(Sim8051::IRegs::PSW)) as usize;
-                        let count = (0x18 & asm.simulator.internal_memory.memory[pswloc]) >> 3;
-                        let val = count * 8 + reg.reg_count();
-                        Some(
-                            asm.simulator.internal_memory.memory
-                                [asm.simulator.internal_memory.memory[val as usize] as usize],
-                        )
-                    }
-                    _ => None,
-                };
-                // trying to unwrap here whitout further checking
-                let accloc = Sim8051::sfr_addr(&asm.simulator.accumulator);
-                let mut acc = asm.simulator.internal_memory.memory[accloc as usize];
-                let unwrapped = val.unwrap();
-                let mut abit;
-                let mut bbit;
-                for i in 0..8 {
-                    abit = (acc & (1 << i)) > 0;
-                    bbit = (unwrapped & (1 << i)) > 0;
-                    let result = operator(abit, bbit);
-                    if result {
-                        acc |= 1 << i;
-                    } else {
-                        acc &= !(1 << i);
-                    }
-                }
-                asm.simulator.internal_memory.memory[accloc as usize] = acc;
-            }
+        format!("Invalid operands to {} : {}", mnemonic, ops.join(", ")).into()
+    }
+}
+
+fn register(op: &str) -> Option<u8> {
+    use std::str::FromStr;
+    Sim8051::ScratchpadRegisters::from_str(op)
+        .ok()
+        .map(|reg| reg.reg_count())
+}
+
+// Only R0 and R1 can be used for indirect addressing
+fn indirect(op: &str) -> Option<u8> {
+    op.strip_prefix('@')
+        .and_then(register)
+        .filter(|reg| *reg < 2)
+}
+
+// Indirect operands are never anything else, so they are kept away from the tokenizer
+fn operand_token(op: &str) -> Option<lexer::TokenType> {
+    if op.starts_with('@') {
+        return None;
+    }
+    Tokenizer::parse_all(op).map(|x| x.token)
+}
+
+// Statements have to end by FFFFH, the location counter never wraps around to 0000H
+fn fits_in_code(addr: u32, len: u32) -> Result<(), String> {
+    if addr + len > 0x10000 {
+        return Err(format!(
+            "{} bytes at {:04X}H run past the end of code memory at FFFFH",
+            len, addr
+        ));
+    }
+    Ok(())
+}
+
+fn fits_byte(value: u16) -> bool {
+    value <= 0xFF || value >= 0xFF80
+}
+
+// Whole operand is a single name, so that TABLE+1 isn't taken for TABLE
+fn identifier(op: &str) -> Option<String> {
+    match operand_token(op) {
+        Some(lexer::TokenType::ID(name)) if name.len() == op.len() => Some(name),
+        _ => None,
+    }
+}
+
+// 'text' or "text" as used by DB
+fn string_literal(op: &str) -> Option<&str> {
+    let quote = op.chars().next().filter(|x| *x == '\'' || *x == '"')?;
+    op.strip_prefix(quote)?.strip_suffix(quote)
+}
+
+// Code part of the line without the indentation and the comment
+fn statement_span(text: &str) -> Range<usize> {
+    let code = Tokenizer::strip_comment(text);
+    let start = code.len() - code.trim_start().len();
+    start..code.trim_end().len()
+}
+
+// Labels and symbol names start the line
+fn word_span(text: &str, word: &str) -> Range<usize> {
+    match text.find(word) {
+        Some(start) => start..start + word.len(),
+        None => statement_span(text),
+    }
+}
+
+// Operands come last and in order, so each one is searched for right before the one after it
+// That keeps a label or an earlier operand with the same text from being taken for it
+// Registers are uppercased by the tokenizer, hence the search ignores case
+fn operand_span(text: &str, ops: &[&str], index: usize) -> Option<Range<usize>> {
+    let code = Tokenizer::strip_comment(text).to_ascii_uppercase();
+    let mut end = code.len();
+    for (i, op) in ops.iter().enumerate().skip(index).rev() {
+        match code[..end].rfind(&op.to_ascii_uppercase()) {
+            Some(start) if i == index => return Some(start..start + op.len()),
+            Some(start) => end = start,
+            // Written with spaces the tokenizer took out, like @A + DPTR
+            None if i == index => return None,
+            None => {}
         }
-        _ => {
-            panic!("Not handling those last two cases");
-        } // and finally on direct addressing mode
+    }
+    None
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02X}", x)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(src: &str) -> Assembler {
+        let mut asm = Assembler::default();
+        asm.read_src_from_string(src.to_string());
+        asm.assemble();
+        asm
+    }
+
+    fn errors(asm: &Assembler) -> Vec<String> {
+        asm.diagnostics.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn forward_reference_in_ds_is_an_error() {
+        let asm = assemble("ds FWD\nL: nop\nFWD equ 4\nsjmp L\n");
+        assert!(asm.has_errors());
+        assert!(errors(&asm)[0].contains("source:1:4: error: Forward reference to FWD"));
+    }
+
+    #[test]
+    fn forward_reference_in_org_is_an_error() {
+        let asm = assemble("org START\nnop\nSTART equ 100h\n");
+        assert!(asm.has_errors());
+        assert!(errors(&asm)[0].contains("Forward reference to START"));
+    }
+
+    #[test]
+    fn org_and_ds_take_symbols_defined_above() {
+        let asm = assemble("FWD equ 4\nds FWD\nL: nop\nsjmp L\n");
+        assert!(!asm.has_errors(), "{:?}", errors(&asm));
+        assert_eq!(asm.symbols["L"].value, 0x0004);
+        assert_eq!(asm.simulator.code_memory[0x05..0x07], [0x80, 0xFD]);
+    }
+
+    #[test]
+    fn code_past_ffffh_is_an_error() {
+        let asm = assemble("org 0FFF0h\ndb '0123456789ABCDEFGH'\n");
+        assert!(errors(&asm)[0].contains("18 bytes at FFF0H run past the end of code memory"));
+        assert_eq!(asm.simulator.code_memory[0x0000], 0x00);
+
+        // Right up to FFFFH is fine
+        let asm = assemble("org 0FFF0h\ndb '0123456789ABCDEF'\n");
+        assert!(!asm.has_errors(), "{:?}", errors(&asm));
+        assert_eq!(asm.end_address, 0x10000);
+    }
+
+    // Hex file read back gives exactly what was put in code memory
+    fn assert_hex_round_trip(asm: &Assembler) {
+        let hex = asm.to_hex();
+        let chunks = ihex::parse(&hex).unwrap();
+        assert!(!chunks.is_empty());
+        for (addr, bytes) in chunks {
+            let start = addr as usize;
+            assert!(start + bytes.len() <= 0x10000, "{:X} in\n{}", addr, hex);
+            assert_eq!(
+                asm.simulator.code_memory[start..start + bytes.len()],
+                bytes[..]
+            );
+        }
+        let mut sim = Sim8051::Sim8051::default();
+        assert!(sim.load_hex_from_string(&hex, 0).is_ok());
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let asm = assemble("mov a, #12h\nsjmp $\norg 100h\ndb 'hello', 0\nds 4\ndw 1234h\n");
+        assert!(!asm.has_errors(), "{:?}", errors(&asm));
+        assert_hex_round_trip(&asm);
+    }
+
+    #[test]
+    fn hex_ends_at_ffffh() {
+        let asm = assemble("org 0FFF0h\ndb '0123456789ABCDEF'\n");
+        assert!(!asm.to_hex().contains(":02000004"));
+        assert_hex_round_trip(&asm);
+
+        // Bytes running past FFFFH are an error and never make it into the hex file
+        let asm = assemble("org 0FFF0h\ndb '0123456789ABCDEFGH'\n");
+        assert!(asm.has_errors());
+        assert!(!asm.to_hex().contains(":02000004"));
+    }
+
+    #[test]
+    fn failed_statement_keeps_its_room() {
+        let asm = assemble("mov a,#UNDEF\nL: nop\n");
+        assert_eq!(errors(&asm), ["source:1:7: error: Undefined symbol UNDEF"]);
+        assert_eq!(asm.symbols["L"].value, 0x0002);
+
+        let asm = assemble("sjmp far\nL1: nop\norg 200h\nfar: nop\n");
+        assert_eq!(errors(&asm).len(), 1);
+        assert!(errors(&asm)[0].contains("far : Jump to 0200H is out of range"));
+    }
+
+    #[test]
+    fn number_forms() {
+        for (src, value) in [
+            ("0x3F", 0x3F),
+            ("17Q", 0x0F),
+            ("17o", 0x0F),
+            ("0FFh", 0xFF),
+            ("01010101B", 0x55),
+            ("'A'", 0x41),
+            ("10", 0x0A),
+        ] {
+            let asm = assemble(&format!("mov a, #{}\n", src));
+            assert!(!asm.has_errors(), "{}", src);
+            assert_eq!(asm.simulator.code_memory[..2], [0x74, value], "{}", src);
+        }
+    }
+
+    #[test]
+    fn byte_range() {
+        for (src, value) in [("255", 0xFF), ("-128", 0x80), ("-1", 0xFF)] {
+            let asm = assemble(&format!("mov a, #{}\n", src));
+            assert!(!asm.has_errors(), "{}", src);
+            assert_eq!(asm.simulator.code_memory[..2], [0x74, value], "{}", src);
+        }
+        for src in ["256", "-129", "0FF00h", "0FF7Fh"] {
+            let asm = assemble(&format!("mov a, #{}\n", src));
+            assert_eq!(
+                errors(&asm),
+                [format!(
+                    "source:1:8: error: Value {} does not fit in 8 bits",
+                    src
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_operand() {
+        let asm = assemble("mov a, #1/0\nmov a,#'\nmov 5, #300\n");
+        let errors = errors(&asm);
+        assert_eq!(
+            errors[0],
+            "source:1:8: error: Division by zero in expression"
+        );
+        assert_eq!(
+            errors[1],
+            "source:2:7: error: Missing closing quote of character constant"
+        );
+        assert_eq!(
+            errors[2],
+            "source:3:8: error: Value 300 does not fit in 8 bits"
+        );
+    }
+
+    #[test]
+    fn negative_ds_is_an_error() {
+        let asm = assemble("ds -1\nnop\n");
+        assert!(errors(&asm)[0].contains("DS count -1 is negative"));
+        let asm = assemble("org 0FFF0h\nds 20h\n");
+        assert!(errors(&asm)[0].contains("run past the end of code memory"));
     }
 }
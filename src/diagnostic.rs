@@ -0,0 +1,65 @@
+// Errors and warnings found in the source, collected so that a single run reports every one of them
+// Rendered the way compilers do     file:line:column: error: message
+
+use std::{fmt, ops::Range};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    pub line: usize,   // starting from 1
+    pub column: usize, // starting from 1, in characters
+    pub message: String,
+    pub span: Range<usize>, // byte range of the offending text within the line
+}
+
+impl Diagnostic {
+    // Column is worked out from the span, text being the whole source line
+    pub fn new(
+        severity: Severity,
+        file: &str,
+        line: usize,
+        text: &str,
+        span: Range<usize>,
+        message: String,
+    ) -> Diagnostic {
+        let column = text.get(..span.start).map_or(0, |x| x.chars().count()) + 1;
+        Diagnostic {
+            severity,
+            file: file.to_string(),
+            line,
+            column,
+            message,
+            span,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.file, self.line, self.column, self.severity, self.message
+        )
+    }
+}
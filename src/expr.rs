@@ -0,0 +1,280 @@
+// Constant expressions used as operands, evaluated in 16 bits like the other 8051 assemblers do
+//
+//  expr   :-  and   { (OR | XOR) and }
+//  and    :-  not   { AND not }
+//  not    :-  NOT not  |  shift
+//  shift  :-  sum   { (SHL | SHR) sum }
+//  sum    :-  term  { (+ | -) term }
+//  term   :-  unary { (* | / | MOD) unary }
+//  unary  :-  (+ | - | HIGH | LOW) unary  |  atom
+//  atom   :-  number  |  'c'  |  name  |  $  |  ( expr )
+//
+// Operator names are not case sensitive, $ is the address of the current statement
+
+use crate::lexer::Tokenizer;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u16),
+    Name(String),
+    Op(&'static str), // operator keywords and + - * /
+    Symbol(char),     // parentheses and $
+}
+
+const KEYWORDS: [&str; 9] = [
+    "MOD", "SHL", "SHR", "AND", "OR", "XOR", "NOT", "HIGH", "LOW",
+];
+
+// Names are resolved through lookup, which decides what to do about the undefined ones
+pub fn evaluate(
+    src: &str,
+    here: u16,
+    lookup: &dyn Fn(&str) -> Result<u16, String>,
+) -> Result<u16, String> {
+    let tokens = tokenize(src)?;
+    if tokens.is_empty() {
+        return Err(String::from("Missing value"));
+    }
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        here,
+        lookup,
+    };
+    let value = parser.expr()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(value),
+        Some(_) => Err(format!("Unexpected text in expression {}", src)),
+    }
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        if ch.is_ascii_alphanumeric() || ch == '_' {
+            let word: String = chars[i..]
+                .iter()
+                .take_while(|x| x.is_ascii_alphanumeric() || **x == '_')
+                .collect();
+            i += word.len();
+            if ch.is_ascii_digit() {
+                tokens.push(Token::Number(Tokenizer::parse_number(&word)?));
+            } else {
+                let upper = word.to_ascii_uppercase();
+                match KEYWORDS.iter().find(|x| **x == upper) {
+                    Some(keyword) => tokens.push(Token::Op(keyword)),
+                    None => tokens.push(Token::Name(word)),
+                }
+            }
+            continue;
+        }
+        // Character constants are one or two characters, 'AB' being 4142H
+        if ch == '\'' {
+            let len = chars[i + 1..]
+                .iter()
+                .position(|x| *x == '\'')
+                .ok_or_else(|| String::from("Missing closing quote of character constant"))?;
+            let text = &chars[i + 1..i + 1 + len];
+            if text.is_empty() || text.len() > 2 || text.iter().any(|x| !x.is_ascii()) {
+                return Err(format!(
+                    "Invalid character constant '{}'",
+                    text.iter().collect::<String>()
+                ));
+            }
+            let value = text.iter().fold(0u16, |value, x| (value << 8) | *x as u16);
+            tokens.push(Token::Number(value));
+            i += len + 2;
+            continue;
+        }
+        match ch {
+            '+' => tokens.push(Token::Op("+")),
+            '-' => tokens.push(Token::Op("-")),
+            '*' => tokens.push(Token::Op("*")),
+            '/' => tokens.push(Token::Op("/")),
+            '(' | ')' | '$' => tokens.push(Token::Symbol(ch)),
+            _ => return Err(format!("Unexpected character '{}' in expression", ch)),
+        }
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    here: u16,
+    lookup: &'a dyn Fn(&str) -> Result<u16, String>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    // Consumes the next token if it is one of the given operators
+    fn accept(&mut self, ops: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self) -> Result<u16, String> {
+        let mut value = self.and()?;
+        while let Some(op) = self.accept(&["OR", "XOR"]) {
+            let rhs = self.and()?;
+            value = if op == "OR" { value | rhs } else { value ^ rhs };
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<u16, String> {
+        let mut value = self.not()?;
+        while self.accept(&["AND"]).is_some() {
+            value &= self.not()?;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<u16, String> {
+        if self.accept(&["NOT"]).is_some() {
+            return Ok(!self.not()?);
+        }
+        self.shift()
+    }
+
+    fn shift(&mut self) -> Result<u16, String> {
+        let mut value = self.sum()?;
+        while let Some(op) = self.accept(&["SHL", "SHR"]) {
+            let count = self.sum()?;
+            // Shifting everything out gives 0 rather than wrapping the count around
+            value = match (op, count) {
+                (_, 16..) => 0,
+                ("SHL", _) => value << count,
+                _ => value >> count,
+            };
+        }
+        Ok(value)
+    }
+
+    fn sum(&mut self) -> Result<u16, String> {
+        let mut value = self.term()?;
+        while let Some(op) = self.accept(&["+", "-"]) {
+            let rhs = self.term()?;
+            value = if op == "+" {
+                value.wrapping_add(rhs)
+            } else {
+                value.wrapping_sub(rhs)
+            };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<u16, String> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.accept(&["*", "/", "MOD"]) {
+            let rhs = self.unary()?;
+            value = match op {
+                "*" => value.wrapping_mul(rhs),
+                _ if rhs == 0 => return Err(String::from("Division by zero in expression")),
+                "/" => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<u16, String> {
+        match self.accept(&["+", "-", "HIGH", "LOW"]) {
+            Some("+") => self.unary(),
+            Some("-") => Ok(self.unary()?.wrapping_neg()),
+            Some("HIGH") => Ok(self.unary()? >> 8),
+            Some(_) => Ok(self.unary()? & 0xFF),
+            None => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<u16, String> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Err(String::from("Expression ends too early")),
+        };
+        self.pos += 1;
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Name(name) => (self.lookup)(&name),
+            Token::Symbol('$') => Ok(self.here),
+            Token::Symbol('(') => {
+                let value = self.expr()?;
+                match self.peek() {
+                    Some(Token::Symbol(')')) => {
+                        self.pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(String::from("Missing closing parenthesis")),
+                }
+            }
+            Token::Symbol(ch) => Err(format!("Unexpected '{}' in expression", ch)),
+            Token::Op(op) => Err(format!("Unexpected {} in expression", op)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(src: &str) -> Result<u16, String> {
+        evaluate(src, 0x0100, &|name| match name {
+            "TABLE" => Ok(0x1234),
+            _ => Err(format!("Undefined symbol {}", name)),
+        })
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("2 + 3 * 4"), Ok(14));
+        assert_eq!(eval("(2 + 3) * 4"), Ok(20));
+        assert_eq!(eval("1 SHL 2 + 1"), Ok(8));
+        assert_eq!(eval("6 or 1 and 3"), Ok(7));
+        assert_eq!(eval("17 mod 5 * 2"), Ok(4));
+        assert_eq!(eval("0 - 1"), Ok(0xFFFF));
+    }
+
+    #[test]
+    fn high_and_low() {
+        assert_eq!(eval("HIGH TABLE"), Ok(0x12));
+        assert_eq!(eval("low(TABLE + 1)"), Ok(0x35));
+        assert_eq!(eval("'AB'"), Ok(0x4142));
+    }
+
+    #[test]
+    fn current_address() {
+        assert_eq!(eval("$"), Ok(0x0100));
+        assert_eq!(eval("$ + 2"), Ok(0x0102));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            eval("1 / 0"),
+            Err(String::from("Division by zero in expression"))
+        );
+        assert_eq!(
+            eval("nowhere"),
+            Err(String::from("Undefined symbol nowhere"))
+        );
+        assert!(eval("(1 + 2").is_err());
+    }
+}
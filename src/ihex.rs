@@ -0,0 +1,220 @@
+// Intel HEX format, as taken by the programmers and other simulators
+// Every record is a line of the form   :LLAAAATT<data>CC
+// LL -> number of data bytes, AAAA -> address, TT -> record type and CC -> two's complement checksum of all bytes
+
+use std::collections::BTreeMap;
+
+pub const DATA_RECORD: u8 = 0x00;
+pub const EOF_RECORD: u8 = 0x01;
+pub const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+pub const START_SEGMENT_ADDRESS: u8 = 0x03;
+pub const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+pub const START_LINEAR_ADDRESS: u8 = 0x05;
+
+// Same record length as Keil and SDCC use
+const BYTES_PER_RECORD: usize = 16;
+
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+pub fn record(kind: u8, addr: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+    bytes.extend_from_slice(data);
+    bytes.push(checksum(&bytes));
+
+    let mut line = String::with_capacity(bytes.len() * 2 + 1);
+    line.push(':');
+    for byte in bytes {
+        line.push_str(&format!("{:02X}", byte));
+    }
+    line
+}
+
+// Turns (address, bytes) chunks into the text of a hex file
+// Chunks may come in any order, contiguous ones are packed together and bytes written twice keep the last value
+// Extended linear address records are emitted whenever the upper 16 bits of the address change
+pub fn write(chunks: &[(u32, Vec<u8>)]) -> String {
+    let mut image = BTreeMap::new();
+    for (addr, bytes) in chunks {
+        for (i, byte) in bytes.iter().enumerate() {
+            image.insert(addr + i as u32, *byte);
+        }
+    }
+
+    let mut out = String::new();
+    let mut upper: u16 = 0x0000;
+    let mut run: Vec<u8> = Vec::with_capacity(BYTES_PER_RECORD);
+    let mut run_start: u32 = 0;
+
+    for (addr, byte) in image {
+        let contiguous = !run.is_empty() && run_start + run.len() as u32 == addr;
+        // Records never cross a 64K boundary as their address is only 16 bits
+        let same_segment = (addr >> 16) == (run_start >> 16);
+        if !(contiguous && same_segment) || run.len() == BYTES_PER_RECORD {
+            flush_run(&mut out, &mut upper, run_start, &mut run);
+            run_start = addr;
+        }
+        run.push(byte);
+    }
+    flush_run(&mut out, &mut upper, run_start, &mut run);
+
+    out.push_str(&record(EOF_RECORD, 0x0000, &[]));
+    out.push('\n');
+    out
+}
+
+fn flush_run(out: &mut String, upper: &mut u16, start: u32, run: &mut Vec<u8>) {
+    if run.is_empty() {
+        return;
+    }
+    if (start >> 16) as u16 != *upper {
+        *upper = (start >> 16) as u16;
+        out.push_str(&record(
+            EXTENDED_LINEAR_ADDRESS,
+            0x0000,
+            &upper.to_be_bytes(),
+        ));
+        out.push('\n');
+    }
+    out.push_str(&record(DATA_RECORD, start as u16, run));
+    out.push('\n');
+    run.clear();
+}
+
+// Parses the text of a hex file back into (address, bytes) chunks, one per data record
+// Every record is checked for its length and checksum, errors carry the line they were found on
+pub fn parse(text: &str) -> Result<Vec<(u32, Vec<u8>)>, String> {
+    let mut chunks = Vec::new();
+    let mut base: u32 = 0;
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bytes = decode_record(line).map_err(|err| format!("Line {} : {}", n + 1, err))?;
+        let len = bytes[0] as usize;
+        let addr = ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        let kind = bytes[3];
+        let data = &bytes[4..4 + len];
+
+        match kind {
+            DATA_RECORD => chunks.push((base + addr, data.to_vec())),
+            EOF_RECORD => return Ok(chunks),
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS => {
+                if len != 2 {
+                    return Err(format!(
+                        "Line {} : Address record should have 2 data bytes, found {}",
+                        n + 1,
+                        len
+                    ));
+                }
+                let value = ((data[0] as u32) << 8) | data[1] as u32;
+                base = if kind == EXTENDED_SEGMENT_ADDRESS {
+                    value << 4
+                } else {
+                    value << 16
+                };
+            }
+            // Start address only matters to x86 loaders, the 8051 always starts at 0000H
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {}
+            _ => {
+                return Err(format!(
+                    "Line {} : Unknown record type {:02X}H",
+                    n + 1,
+                    kind
+                ))
+            }
+        }
+    }
+    Err(String::from(
+        "Missing end of file record, the hex file may be truncated",
+    ))
+}
+
+// Decodes a single record into its bytes after validating its shape and checksum
+fn decode_record(line: &str) -> Result<Vec<u8>, String> {
+    let digits = match line.strip_prefix(':') {
+        Some(digits) => digits,
+        None => return Err(String::from("Record should start with ':'")),
+    };
+    if !digits.chars().all(|x| x.is_ascii_hexdigit()) {
+        return Err(String::from("Record contains non hex characters"));
+    }
+    if digits.len() % 2 != 0 || digits.len() < 10 {
+        return Err(String::from(
+            "Record is too short or has an odd number of digits",
+        ));
+    }
+
+    let bytes: Vec<u8> = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or(0))
+        .collect();
+
+    let len = bytes[0] as usize;
+    if bytes.len() != len + 5 {
+        return Err(format!(
+            "Record says {} data bytes but has {}",
+            len,
+            bytes.len() - 5
+        ));
+    }
+    let (body, sum) = bytes.split_at(bytes.len() - 1);
+    let expected = checksum(body);
+    if sum[0] != expected {
+        return Err(format!(
+            "Checksum mismatch, found {:02X}H but expected {:02X}H",
+            sum[0], expected
+        ));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_of_a_record() {
+        assert_eq!(checksum(&[0x03, 0x00, 0x30, 0x00, 0x02, 0x33, 0x7A]), 0x1E);
+        assert_eq!(record(EOF_RECORD, 0x0000, &[]), ":00000001FF");
+    }
+
+    #[test]
+    fn write_and_parse() {
+        let data: Vec<u8> = (0..40).collect();
+        let text = write(&[(0x0100, data.clone()), (0x1FFF8, vec![0xAA; 16])]);
+        let chunks = parse(&text).unwrap();
+
+        let mut image = vec![];
+        for (addr, bytes) in chunks {
+            assert!(bytes.len() <= 16);
+            for (i, byte) in bytes.into_iter().enumerate() {
+                image.push((addr + i as u32, byte));
+            }
+        }
+        let mut expected: Vec<(u32, u8)> = (0..40).map(|i| (0x0100 + i as u32, i)).collect();
+        expected.extend((0..16).map(|i| (0x1FFF8 + i, 0xAA)));
+        assert_eq!(image, expected);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let err = parse(":0300300002337A1F\n:00000001FF\n").unwrap_err();
+        assert_eq!(
+            err,
+            "Line 1 : Checksum mismatch, found 1FH but expected 1EH"
+        );
+    }
+
+    #[test]
+    fn missing_end_of_file() {
+        let err = parse(":0300300002337A1E\n").unwrap_err();
+        assert!(err.starts_with("Missing end of file record"));
+    }
+}
@@ -0,0 +1,586 @@
+// Typed model of the 8051 instruction set, shared by the assembler, the core and the disassembler
+// Operands are kept the way they are written, so ADD A,R0 is Add(Register(0)) with the accumulator implied
+// Jump targets are always the address they lead to, offsets are only worked out while encoding and decoding
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    A,
+    AB,
+    C,
+    Dptr,
+    Direct(u8),
+    Immediate(u8),
+    Immediate16(u16), // only for MOV DPTR,#data16
+    Register(u8),     // R0 - R7 of the active register bank
+    Indirect(u8),     // @R0 and @R1
+    IndirectDptr,     // @DPTR of MOVX
+    IndirectAccDptr,  // @A+DPTR
+    IndirectAccPc,    // @A+PC
+    Bit(u8),
+    NotBit(u8),    // /bit of ANL C and ORL C
+    Relative(u16), // within -128 to +127 bytes of the next instruction
+    Absolute(u16), // within the 2K block of the next instruction
+    Long(u16),
+}
+
+impl Operand {
+    pub fn target(&self) -> Option<u16> {
+        match self {
+            Operand::Relative(addr) | Operand::Absolute(addr) | Operand::Long(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    // Bytes the operand takes after the opcode
+    fn size(&self) -> u16 {
+        use Operand::*;
+        match self {
+            Direct(_) | Immediate(_) | Bit(_) | NotBit(_) | Relative(_) | Absolute(_) => 1,
+            Immediate16(_) | Long(_) => 2,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Acall(Operand),
+    Add(Operand),
+    Addc(Operand),
+    Ajmp(Operand),
+    Anl(Operand, Operand),
+    Cjne(Operand, Operand, Operand),
+    Clr(Operand),
+    Cpl(Operand),
+    Da,
+    Dec(Operand),
+    Div,
+    Djnz(Operand, Operand),
+    Inc(Operand),
+    Jb(Operand, Operand),
+    Jbc(Operand, Operand),
+    Jc(Operand),
+    Jmp, // JMP @A+DPTR
+    Jnb(Operand, Operand),
+    Jnc(Operand),
+    Jnz(Operand),
+    Jz(Operand),
+    Lcall(Operand),
+    Ljmp(Operand),
+    Mov(Operand, Operand),
+    Movc(Operand),
+    Movx(Operand, Operand),
+    Mul,
+    Nop,
+    Orl(Operand, Operand),
+    Pop(Operand),
+    Push(Operand),
+    Ret,
+    Reti,
+    Rl,
+    Rlc,
+    Rr,
+    Rrc,
+    Setb(Operand),
+    Sjmp(Operand),
+    Subb(Operand),
+    Swap,
+    Xch(Operand),
+    Xchd(Operand),
+    Xrl(Operand, Operand),
+    Undefined(u8), // A5H, the only opcode without an instruction
+}
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        use Instruction::*;
+        match self {
+            Acall(_) => "ACALL",
+            Add(_) => "ADD",
+            Addc(_) => "ADDC",
+            Ajmp(_) => "AJMP",
+            Anl(..) => "ANL",
+            Cjne(..) => "CJNE",
+            Clr(_) => "CLR",
+            Cpl(_) => "CPL",
+            Da => "DA",
+            Dec(_) => "DEC",
+            Div => "DIV",
+            Djnz(..) => "DJNZ",
+            Inc(_) => "INC",
+            Jb(..) => "JB",
+            Jbc(..) => "JBC",
+            Jc(_) => "JC",
+            Jmp => "JMP",
+            Jnb(..) => "JNB",
+            Jnc(_) => "JNC",
+            Jnz(_) => "JNZ",
+            Jz(_) => "JZ",
+            Lcall(_) => "LCALL",
+            Ljmp(_) => "LJMP",
+            Mov(..) => "MOV",
+            Movc(_) => "MOVC",
+            Movx(..) => "MOVX",
+            Mul => "MUL",
+            Nop => "NOP",
+            Orl(..) => "ORL",
+            Pop(_) => "POP",
+            Push(_) => "PUSH",
+            Ret => "RET",
+            Reti => "RETI",
+            Rl => "RL",
+            Rlc => "RLC",
+            Rr => "RR",
+            Rrc => "RRC",
+            Setb(_) => "SETB",
+            Sjmp(_) => "SJMP",
+            Subb(_) => "SUBB",
+            Swap => "SWAP",
+            Xch(_) => "XCH",
+            Xchd(_) => "XCHD",
+            Xrl(..) => "XRL",
+            Undefined(_) => "DB",
+        }
+    }
+
+    // Operands as written in the source, the implied accumulator included
+    pub fn operands(&self) -> Vec<Operand> {
+        use Instruction::*;
+        use Operand::*;
+        match *self {
+            Acall(x) | Ajmp(x) | Clr(x) | Cpl(x) | Dec(x) | Inc(x) | Jc(x) | Jnc(x) | Jnz(x)
+            | Jz(x) | Lcall(x) | Ljmp(x) | Pop(x) | Push(x) | Setb(x) | Sjmp(x) => vec![x],
+            Add(x) | Addc(x) | Movc(x) | Subb(x) | Xch(x) | Xchd(x) => vec![A, x],
+            Anl(x, y)
+            | Djnz(x, y)
+            | Jb(x, y)
+            | Jbc(x, y)
+            | Jnb(x, y)
+            | Mov(x, y)
+            | Movx(x, y)
+            | Orl(x, y)
+            | Xrl(x, y) => vec![x, y],
+            Cjne(x, y, z) => vec![x, y, z],
+            Da | Rl | Rlc | Rr | Rrc | Swap => vec![A],
+            Mul | Div => vec![AB],
+            Jmp => vec![IndirectAccDptr],
+            Nop | Ret | Reti | Undefined(_) => vec![],
+        }
+    }
+
+    // Every 8051 opcode has a fixed length, which only depends on the kind of its operands
+    pub fn size(&self) -> u16 {
+        1 + self.operands().iter().map(|x| x.size()).sum::<u16>()
+    }
+
+    // Whether the operands are a combination the 8051 actually has
+    pub fn is_valid(&self) -> bool {
+        self.encode_raw(0x0000).is_some()
+    }
+
+    // Machine code of the instruction placed at addr .. jump targets have to be reachable from there
+    pub fn encode(&self, addr: u16) -> Result<Vec<u8>, String> {
+        let next = addr.wrapping_add(self.size());
+        for op in self.operands() {
+            match op {
+                Operand::Relative(target) => {
+                    let offset = target.wrapping_sub(next) as i16;
+                    if !(-128..=127).contains(&offset) {
+                        return Err(format!(
+                            "Jump to {} is out of range by {} bytes",
+                            hex16(target),
+                            if offset < 0 {
+                                -128 - offset as i32
+                            } else {
+                                offset as i32 - 127
+                            }
+                        ));
+                    }
+                }
+                // Only 11 bits are encoded, rest are taken from the address of next instruction
+                Operand::Absolute(target) if target & 0xF800 != next & 0xF800 => {
+                    return Err(format!(
+                        "{} target {} is outside the current 2K block",
+                        self.mnemonic(),
+                        hex16(target)
+                    ));
+                }
+                _ => {}
+            }
+        }
+        self.encode_raw(addr).ok_or_else(|| {
+            format!(
+                "Invalid operands to {} : {}",
+                self.mnemonic(),
+                self.operand_text()
+            )
+        })
+    }
+
+    // Encodes without checking the jump ranges, None for operand combinations that don't exist
+    fn encode_raw(&self, addr: u16) -> Option<Vec<u8>> {
+        use Instruction::*;
+        use Operand::*;
+        let mut bytes = match *self {
+            Nop => vec![0x00],
+            Undefined(op) => vec![op],
+            Ajmp(Absolute(target)) => {
+                vec![(((target >> 8) & 0x07) as u8) << 5 | 0x01, target as u8]
+            }
+            Acall(Absolute(target)) => {
+                vec![(((target >> 8) & 0x07) as u8) << 5 | 0x11, target as u8]
+            }
+            Ljmp(Long(target)) => vec![0x02, (target >> 8) as u8, target as u8],
+            Lcall(Long(target)) => vec![0x12, (target >> 8) as u8, target as u8],
+            Ret => vec![0x22],
+            Reti => vec![0x32],
+            Jmp => vec![0x73],
+            Sjmp(Relative(_)) => vec![0x80, 0x00],
+            Jc(Relative(_)) => vec![0x40, 0x00],
+            Jnc(Relative(_)) => vec![0x50, 0x00],
+            Jz(Relative(_)) => vec![0x60, 0x00],
+            Jnz(Relative(_)) => vec![0x70, 0x00],
+            Jbc(Bit(bit), Relative(_)) => vec![0x10, bit, 0x00],
+            Jb(Bit(bit), Relative(_)) => vec![0x20, bit, 0x00],
+            Jnb(Bit(bit), Relative(_)) => vec![0x30, bit, 0x00],
+
+            Rr => vec![0x03],
+            Rrc => vec![0x13],
+            Rl => vec![0x23],
+            Rlc => vec![0x33],
+            Swap => vec![0xC4],
+            Da => vec![0xD4],
+            Mul => vec![0xA4],
+            Div => vec![0x84],
+
+            Inc(A) => vec![0x04],
+            Dec(A) => vec![0x14],
+            Inc(Dptr) => vec![0xA3],
+            Inc(Immediate(_)) | Dec(Immediate(_)) => return None,
+            Inc(op) => group(0x00, op)?,
+            Dec(op) => group(0x10, op)?,
+            Add(op) => group(0x20, op)?,
+            Addc(op) => group(0x30, op)?,
+            Subb(op) => group(0x90, op)?,
+
+            Orl(C, Bit(bit)) => vec![0x72, bit],
+            Anl(C, Bit(bit)) => vec![0x82, bit],
+            Orl(C, NotBit(bit)) => vec![0xA0, bit],
+            Anl(C, NotBit(bit)) => vec![0xB0, bit],
+            Orl(dst, src) => logic(0x40, dst, src)?,
+            Anl(dst, src) => logic(0x50, dst, src)?,
+            Xrl(dst, src) => logic(0x60, dst, src)?,
+
+            Mov(C, Bit(bit)) => vec![0xA2, bit],
+            Mov(Bit(bit), C) => vec![0x92, bit],
+            Mov(Dptr, Immediate16(data)) => vec![0x90, (data >> 8) as u8, data as u8],
+            Mov(A, Immediate(data)) => vec![0x74, data],
+            // E4 is CLR A rather than MOV A,#data
+            Mov(A, src @ (Direct(_) | Indirect(_) | Register(_))) => group(0xE0, src)?,
+            Mov(Register(n), A) => vec![0xF8 + n],
+            Mov(Register(n), Immediate(data)) => vec![0x78 + n, data],
+            Mov(Register(n), Direct(src)) => vec![0xA8 + n, src],
+            Mov(Indirect(i), A) => vec![0xF6 + i],
+            Mov(Indirect(i), Immediate(data)) => vec![0x76 + i, data],
+            Mov(Indirect(i), Direct(src)) => vec![0xA6 + i, src],
+            Mov(Direct(dst), A) => vec![0xF5, dst],
+            Mov(Direct(dst), Register(n)) => vec![0x88 + n, dst],
+            Mov(Direct(dst), Indirect(i)) => vec![0x86 + i, dst],
+            Mov(Direct(dst), Immediate(data)) => vec![0x75, dst, data],
+            // Source comes first in the encoding of direct to direct move
+            Mov(Direct(dst), Direct(src)) => vec![0x85, src, dst],
+
+            Movc(IndirectAccDptr) => vec![0x93],
+            Movc(IndirectAccPc) => vec![0x83],
+            Movx(A, IndirectDptr) => vec![0xE0],
+            Movx(A, Indirect(i)) => vec![0xE2 + i],
+            Movx(IndirectDptr, A) => vec![0xF0],
+            Movx(Indirect(i), A) => vec![0xF2 + i],
+
+            Push(Direct(addr)) => vec![0xC0, addr],
+            Pop(Direct(addr)) => vec![0xD0, addr],
+
+            Clr(A) => vec![0xE4],
+            Clr(C) => vec![0xC3],
+            Clr(Bit(bit)) => vec![0xC2, bit],
+            Setb(C) => vec![0xD3],
+            Setb(Bit(bit)) => vec![0xD2, bit],
+            Cpl(A) => vec![0xF4],
+            Cpl(C) => vec![0xB3],
+            Cpl(Bit(bit)) => vec![0xB2, bit],
+
+            Djnz(Register(n), Relative(_)) => vec![0xD8 + n, 0x00],
+            Djnz(Direct(addr), Relative(_)) => vec![0xD5, addr, 0x00],
+            Xch(Immediate(_)) => return None,
+            Xch(op) => group(0xC0, op)?,
+            Xchd(Indirect(i)) => vec![0xD6 + i],
+
+            Cjne(A, Immediate(data), Relative(_)) => vec![0xB4, data, 0x00],
+            Cjne(A, Direct(addr), Relative(_)) => vec![0xB5, addr, 0x00],
+            Cjne(Indirect(i), Immediate(data), Relative(_)) => vec![0xB6 + i, data, 0x00],
+            Cjne(Register(n), Immediate(data), Relative(_)) => vec![0xB8 + n, data, 0x00],
+
+            _ => return None,
+        };
+
+        // Relative offset is always the last byte, counted from the next instruction
+        if let Some(target) = self
+            .operands()
+            .iter()
+            .find(|x| matches!(x, Relative(_)))
+            .and_then(|x| x.target())
+        {
+            let next = addr.wrapping_add(bytes.len() as u16);
+            if let Some(last) = bytes.last_mut() {
+                *last = target.wrapping_sub(next) as u8;
+            }
+        }
+        Some(bytes)
+    }
+
+    // Decodes the instruction at addr, code being the whole 64K of code memory
+    pub fn decode(code: &[u8], addr: u16) -> Instruction {
+        use Instruction::*;
+        use Operand::*;
+        let byte = |i: u16| code[addr.wrapping_add(i) as usize % code.len()];
+        let opcode = byte(0);
+        let (first, second) = (byte(1), byte(2));
+        let relative = |len: u16, offset: u8| {
+            Relative(addr.wrapping_add(len).wrapping_add(offset as i8 as u16))
+        };
+        // Low nibble of most of the opcodes selects the operand : 4 -> #data, 5 -> direct, 6/7 -> @Ri and 8..F -> Rn
+        let low = opcode & 0x0F;
+        let src = match low {
+            0x04 => Immediate(first),
+            0x05 => Direct(first),
+            0x06 | 0x07 => Indirect(low & 0x01),
+            _ => Register(low & 0x07),
+        };
+
+        match opcode {
+            0x00 => Nop,
+            op if op & 0x1F == 0x01 || op & 0x1F == 0x11 => {
+                let next = addr.wrapping_add(2);
+                let target = (next & 0xF800) | ((op as u16 & 0xE0) << 3) | first as u16;
+                if op & 0x10 == 0 {
+                    Ajmp(Absolute(target))
+                } else {
+                    Acall(Absolute(target))
+                }
+            }
+            0x02 => Ljmp(Long(((first as u16) << 8) | second as u16)),
+            0x12 => Lcall(Long(((first as u16) << 8) | second as u16)),
+            0x22 => Ret,
+            0x32 => Reti,
+            0x73 => Jmp,
+            0x80 => Sjmp(relative(2, first)),
+            0x40 => Jc(relative(2, first)),
+            0x50 => Jnc(relative(2, first)),
+            0x60 => Jz(relative(2, first)),
+            0x70 => Jnz(relative(2, first)),
+            0x10 => Jbc(Bit(first), relative(3, second)),
+            0x20 => Jb(Bit(first), relative(3, second)),
+            0x30 => Jnb(Bit(first), relative(3, second)),
+
+            0x03 => Rr,
+            0x13 => Rrc,
+            0x23 => Rl,
+            0x33 => Rlc,
+            0xC4 => Swap,
+            0xD4 => Da,
+            0xA4 => Mul,
+            0x84 => Div,
+
+            0x04 => Inc(A),
+            0x14 => Dec(A),
+            0xA3 => Inc(Dptr),
+            0x05..=0x0F => Inc(src),
+            0x15..=0x1F => Dec(src),
+            0x24..=0x2F => Add(src),
+            0x34..=0x3F => Addc(src),
+            0x94..=0x9F => Subb(src),
+
+            0x72 => Orl(C, Bit(first)),
+            0x82 => Anl(C, Bit(first)),
+            0xA0 => Orl(C, NotBit(first)),
+            0xB0 => Anl(C, NotBit(first)),
+            // 4x is ORL, 5x is ANL and 6x is XRL
+            0x42..=0x4F | 0x52..=0x5F | 0x62..=0x6F => {
+                let (dst, src) = match low {
+                    0x02 => (Direct(first), A),
+                    0x03 => (Direct(first), Immediate(second)),
+                    _ => (A, src),
+                };
+                match opcode & 0xF0 {
+                    0x40 => Orl(dst, src),
+                    0x50 => Anl(dst, src),
+                    _ => Xrl(dst, src),
+                }
+            }
+
+            0xA2 => Mov(C, Bit(first)),
+            0x92 => Mov(Bit(first), C),
+            0x90 => Mov(Dptr, Immediate16(((first as u16) << 8) | second as u16)),
+            0x74 => Mov(A, Immediate(first)),
+            0x75 => Mov(Direct(first), Immediate(second)),
+            0x76..=0x7F => Mov(src, Immediate(first)),
+            0x85 => Mov(Direct(second), Direct(first)),
+            0x86..=0x8F => Mov(Direct(first), src),
+            0xA6..=0xAF => Mov(src, Direct(first)),
+            0xE5..=0xEF => Mov(A, src),
+            0xF5..=0xFF => Mov(src, A),
+
+            0x83 => Movc(IndirectAccPc),
+            0x93 => Movc(IndirectAccDptr),
+            0xE0 => Movx(A, IndirectDptr),
+            0xE2 | 0xE3 => Movx(A, Indirect(low & 0x01)),
+            0xF0 => Movx(IndirectDptr, A),
+            0xF2 | 0xF3 => Movx(Indirect(low & 0x01), A),
+
+            0xC0 => Push(Direct(first)),
+            0xD0 => Pop(Direct(first)),
+
+            0xE4 => Clr(A),
+            0xC3 => Clr(C),
+            0xC2 => Clr(Bit(first)),
+            0xD3 => Setb(C),
+            0xD2 => Setb(Bit(first)),
+            0xF4 => Cpl(A),
+            0xB3 => Cpl(C),
+            0xB2 => Cpl(Bit(first)),
+
+            0xD5 => Djnz(Direct(first), relative(3, second)),
+            0xD8..=0xDF => Djnz(src, relative(2, first)),
+            0xC5..=0xCF => Xch(src),
+            0xD6 | 0xD7 => Xchd(src),
+
+            0xB4 => Cjne(A, Immediate(first), relative(3, second)),
+            0xB5 => Cjne(A, Direct(first), relative(3, second)),
+            0xB6..=0xBF => Cjne(src, Immediate(first), relative(3, second)),
+
+            op => Undefined(op),
+        }
+    }
+
+    fn operand_text(&self) -> String {
+        self.operands()
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+// Most of the instructions working on the accumulator follow the same layout in the opcode map
+// base + 4 -> #data, base + 5 -> direct, base + 6 + i -> @Ri and base + 8 + n -> Rn
+fn group(base: u8, op: Operand) -> Option<Vec<u8>> {
+    match op {
+        Operand::Immediate(data) => Some(vec![base + 0x04, data]),
+        Operand::Direct(addr) => Some(vec![base + 0x05, addr]),
+        Operand::Indirect(i) => Some(vec![base + 0x06 + i]),
+        Operand::Register(n) => Some(vec![base + 0x08 + n]),
+        _ => None,
+    }
+}
+
+// ANL, ORL and XRL either work on the accumulator or on a direct address
+fn logic(base: u8, dst: Operand, src: Operand) -> Option<Vec<u8>> {
+    match (dst, src) {
+        (Operand::A, src) => group(base, src),
+        (Operand::Direct(addr), Operand::A) => Some(vec![base + 0x02, addr]),
+        (Operand::Direct(addr), Operand::Immediate(data)) => Some(vec![base + 0x03, addr, data]),
+        _ => None,
+    }
+}
+
+// Hex the way the assemblers want it, with a leading zero when it would start with a letter
+fn hex8(val: u8) -> String {
+    let text = format!("{:02X}H", val);
+    if text.starts_with(|x: char| x.is_ascii_alphabetic()) {
+        format!("0{}", text)
+    } else {
+        text
+    }
+}
+
+fn hex16(val: u16) -> String {
+    let text = format!("{:04X}H", val);
+    if text.starts_with(|x: char| x.is_ascii_alphabetic()) {
+        format!("0{}", text)
+    } else {
+        text
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Operand::*;
+        match self {
+            A => write!(f, "A"),
+            AB => write!(f, "AB"),
+            C => write!(f, "C"),
+            Dptr => write!(f, "DPTR"),
+            Direct(addr) | Bit(addr) => write!(f, "{}", hex8(*addr)),
+            Immediate(data) => write!(f, "#{}", hex8(*data)),
+            Immediate16(data) => write!(f, "#{}", hex16(*data)),
+            Register(n) => write!(f, "R{}", n),
+            Indirect(i) => write!(f, "@R{}", i),
+            IndirectDptr => write!(f, "@DPTR"),
+            IndirectAccDptr => write!(f, "@A+DPTR"),
+            IndirectAccPc => write!(f, "@A+PC"),
+            NotBit(bit) => write!(f, "/{}", hex8(*bit)),
+            Relative(addr) | Absolute(addr) | Long(addr) => write!(f, "{}", hex16(*addr)),
+        }
+    }
+}
+
+// This is the disassembler .. MOV A, #12H
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operands = match self {
+            // Undefined opcode is shown as the data byte it is
+            Instruction::Undefined(op) => hex8(*op),
+            _ => self.operand_text(),
+        };
+        if operands.is_empty() {
+            write!(f, "{}", self.mnemonic())
+        } else {
+            write!(f, "{:<6}{}", self.mnemonic(), operands)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every opcode decoded and encoded again at a few places, including the end of a 2K page for AJMP/ACALL
+    #[test]
+    fn all_opcodes_round_trip() {
+        for addr in [0x0000u16, 0x0100, 0x07FE, 0xF000] {
+            for opcode in 0..=0xFFu8 {
+                let mut code = vec![0u8; 0x10000];
+                code[addr as usize..addr as usize + 3].copy_from_slice(&[opcode, 0x12, 0x34]);
+                let instruction = Instruction::decode(&code, addr);
+                let bytes = instruction.encode(addr).unwrap();
+                assert_eq!(
+                    bytes,
+                    code[addr as usize..addr as usize + instruction.size() as usize],
+                    "{:02X}H at {:04X}H decoded as {}",
+                    opcode,
+                    addr,
+                    instruction
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn disassembly() {
+        let text = |code: &[u8]| Instruction::decode(code, 0x0000).to_string();
+        assert_eq!(text(&[0x74, 0x12]), "MOV   A, #12H");
+        assert_eq!(text(&[0xA5]), "DB    0A5H");
+    }
+}
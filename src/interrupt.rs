@@ -0,0 +1,262 @@
+// Interrupt system of the 8051/8052, polled after every instruction
+//
+// IE    EA - ET2 ES ET1 EX1 ET0 EX0
+// IP    - - PT2 PS PT1 PX1 PT0 PX0
+//
+// A pending interrupt is taken with a hardware LCALL to its vector (2 machine cycles) unless
+//  - an interrupt of the same or a higher priority is in service, a high priority one is never interrupted
+//  - the instruction just executed was RETI or wrote IE or IP, then one more instruction runs first
+// Within the same priority level the sources are polled in the order below
+//
+// The timer 0/1 flags and edge triggered IE0/IE1 are cleared as the vector is taken, RI/TI, TF2 and EXF2 are left to the ISR
+//
+// INT0 (P3.2) and INT1 (P3.3) are sampled once every machine cycle
+//  IT0/IT1 set     -> falling edge, IE0/IE1 is latched when the pin was high one cycle and low the next
+//  IT0/IT1 cleared -> low level, IE0/IE1 follows the pin and the ISR has to get the pin back high before it returns
+
+use crate::Sim8051::{bits, Sim8051};
+
+// Source of an interrupt in polling order, (flags, enable bit, priority bit, vector)
+const SOURCES: [(&[u8], u8, u8, u16); 6] = [
+    (&[bits::IE0], bits::EX0, bits::PX0, 0x0003),
+    (&[bits::TF0], bits::ET0, bits::PT0, 0x000B),
+    (&[bits::IE1], bits::EX1, bits::PX1, 0x0013),
+    (&[bits::TF1], bits::ET1, bits::PT1, 0x001B),
+    (&[bits::RI, bits::TI], bits::ES, bits::PS, 0x0023),
+    (&[bits::TF2, bits::EXF2], bits::ET2, bits::PT2, 0x002B),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Interrupts {
+    // Priority latches, index 0 is the low level and 1 the high level
+    in_service: [bool; 2],
+    // Set by RETI and writes to IE/IP, holds off the next poll
+    hold: bool,
+    // Levels of INT0 and INT1 sampled in the previous machine cycle
+    int_sampled: [bool; 2],
+}
+
+impl Default for Interrupts {
+    fn default() -> Interrupts {
+        Interrupts {
+            in_service: [false; 2],
+            hold: false,
+            int_sampled: [true; 2],
+        }
+    }
+}
+
+impl Interrupts {
+    pub fn in_service(&self) -> bool {
+        self.in_service[0] || self.in_service[1]
+    }
+
+    pub(crate) fn hold(&mut self) {
+        self.hold = true;
+    }
+}
+
+impl Sim8051 {
+    // Whether any source can interrupt at all, a program idling in SJMP $ isn't done while one can
+    pub fn interrupts_enabled(&self) -> bool {
+        self.read_bit(bits::EA) && SOURCES.iter().any(|source| self.read_bit(source.1))
+    }
+
+    // Host side of the INT0 (P3.2) and INT1 (P3.3) pins
+    pub fn drive_int0(&mut self, level: bool) {
+        self.drive_pin(bits::INT0, level);
+    }
+
+    pub fn drive_int1(&mut self, level: bool) {
+        self.drive_pin(bits::INT1, level);
+    }
+
+    // One machine cycle of sampling INT0 and INT1 into IE0 and IE1
+    pub fn sample_external_interrupts(&mut self) {
+        let pins = [
+            (bits::INT0, bits::IT0, bits::IE0),
+            (bits::INT1, bits::IT1, bits::IE1),
+        ];
+        for (n, (pin, edge_triggered, flag)) in pins.into_iter().enumerate() {
+            let level = self.pin(pin);
+            if self.read_bit(edge_triggered) {
+                if self.interrupts.int_sampled[n] && !level {
+                    self.write_bit(flag, true);
+                }
+            } else {
+                self.write_bit(flag, !level);
+            }
+            self.interrupts.int_sampled[n] = level;
+        }
+    }
+
+    // Polls the sources and vectors to the one taken, returns the machine cycles spent on it
+    pub fn poll_interrupts(&mut self) -> u8 {
+        if std::mem::take(&mut self.interrupts.hold) || !self.read_bit(bits::EA) {
+            return 0;
+        }
+        let pending = |sim: &Sim8051, high: bool| {
+            SOURCES.iter().position(|(flags, enable, priority, _)| {
+                sim.read_bit(*enable)
+                    && sim.read_bit(*priority) == high
+                    && flags.iter().any(|flag| sim.read_bit(*flag))
+            })
+        };
+        let taken = match (pending(self, true), pending(self, false)) {
+            (Some(source), _) if !self.interrupts.in_service[1] => (source, 1),
+            (_, Some(source)) if !self.interrupts.in_service() => (source, 0),
+            _ => return 0,
+        };
+        let (source, level) = taken;
+        self.interrupts.in_service[level] = true;
+        self.acknowledge(source);
+        self.call(SOURCES[source].3);
+        2
+    }
+
+    // RETI frees the highest priority level in service
+    pub(crate) fn return_from_interrupt(&mut self) {
+        self.ret();
+        let level = if self.interrupts.in_service[1] { 1 } else { 0 };
+        self.interrupts.in_service[level] = false;
+        self.interrupts.hold();
+    }
+
+    fn acknowledge(&mut self, source: usize) {
+        match source {
+            0 if self.read_bit(bits::IT0) => self.write_bit(bits::IE0, false),
+            1 => self.write_bit(bits::TF0, false),
+            2 if self.read_bit(bits::IT1) => self.write_bit(bits::IE1, false),
+            3 => self.write_bit(bits::TF1, false),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn program(src: &str) -> Sim8051 {
+        let mut asm = Assembler::default();
+        asm.read_src_from_string(src.to_string());
+        assert!(asm.assemble(), "{:?}", asm.diagnostics);
+        asm.simulator
+    }
+
+    // Both INT0 and timer 0 enabled, the ISRs are plain NOPs that never return
+    const TWO_SOURCES: &str = "mov ie, #83h\nnop\nnop\norg 0Bh\nnop\nnop\n";
+
+    #[test]
+    fn high_priority_preempts_low_priority() {
+        let mut sim = program(&format!("setb px0\n{}", TWO_SOURCES));
+        sim.step();
+        sim.step();
+        sim.write_bit(bits::TF0, true);
+        sim.step();
+        assert_eq!(sim.pc(), 0x000B);
+
+        sim.drive_int0(false);
+        sim.step();
+        assert_eq!(sim.pc(), 0x0003);
+    }
+
+    #[test]
+    fn low_priority_waits_for_high_priority() {
+        let mut sim = program(&format!("setb pt0\n{}", TWO_SOURCES));
+        sim.step();
+        sim.step();
+        sim.write_bit(bits::TF0, true);
+        sim.step();
+        assert_eq!(sim.pc(), 0x000B);
+
+        sim.drive_int0(false);
+        sim.step();
+        assert_eq!(sim.pc(), 0x000C);
+        assert!(sim.read_bit(bits::IE0));
+    }
+
+    #[test]
+    fn reti_and_ie_writes_hold_off_one_instruction() {
+        let mut sim = program("setb tf0\nmov ie, #82h\nnop\nnop\nnop\norg 0Bh\nreti\n");
+        sim.step();
+        sim.step();
+        assert_eq!(sim.pc(), 0x0005);
+        sim.step();
+        assert_eq!(sim.pc(), 0x000B);
+
+        // Pending again before the ISR returns
+        sim.write_bit(bits::TF0, true);
+        sim.step();
+        assert_eq!(sim.pc(), 0x0006);
+        sim.step();
+        assert_eq!(sim.pc(), 0x000B);
+    }
+
+    #[test]
+    fn vectoring_clears_timer_and_edge_flags_only() {
+        let mut sim = program("mov ie, #83h\nsetb it0\nnop\n");
+        sim.step();
+        sim.write_bit(bits::TF0, true);
+        sim.drive_int0(false);
+        sim.step();
+        assert_eq!(sim.pc(), 0x0003);
+        assert!(!sim.read_bit(bits::IE0));
+        assert!(sim.read_bit(bits::TF0));
+
+        let mut sim = program("mov ie, #82h\nnop\n");
+        sim.step();
+        sim.write_bit(bits::TF0, true);
+        sim.step();
+        assert_eq!(sim.pc(), 0x000B);
+        assert!(!sim.read_bit(bits::TF0));
+
+        let mut sim = program("mov ie, #90h\nnop\n");
+        sim.step();
+        sim.write_bit(bits::RI, true);
+        sim.write_bit(bits::TI, true);
+        sim.step();
+        assert_eq!(sim.pc(), 0x0023);
+        assert!(sim.read_bit(bits::RI) && sim.read_bit(bits::TI));
+    }
+
+    // IE0 after each sample of INT0 at the given levels
+    fn ie0_samples(sim: &mut Sim8051, levels: &[bool]) -> Vec<bool> {
+        levels
+            .iter()
+            .map(|level| {
+                sim.drive_int0(*level);
+                sim.sample_external_interrupts();
+                sim.read_bit(bits::IE0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edge_triggered_int0_latches_falling_edges() {
+        let mut sim = Sim8051::default();
+        sim.write_bit(bits::IT0, true);
+        assert_eq!(
+            ie0_samples(&mut sim, &[true, false, false]),
+            [false, true, true]
+        );
+        // Cleared by the ISR, it stays clear while the pin stays low
+        sim.write_bit(bits::IE0, false);
+        assert_eq!(
+            ie0_samples(&mut sim, &[false, true, true, false]),
+            [false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn level_triggered_int0_follows_the_pin() {
+        let mut sim = Sim8051::default();
+        assert_eq!(
+            ie0_samples(&mut sim, &[true, false, false, true, false]),
+            [false, true, true, false, true]
+        );
+        sim.write_bit(bits::IE0, false);
+        assert_eq!(ie0_samples(&mut sim, &[false]), [true]);
+    }
+}
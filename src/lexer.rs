@@ -4,6 +4,7 @@ use std::io::Read;
 // Lets work in regex for tokenizing
 use crate::Sim8051;
 
+#[allow(non_camel_case_types)]
 #[derive(Debug)]
 pub enum TokenType {
     ID(String),
@@ -12,12 +13,15 @@ pub enum TokenType {
     LINE,
     HEX(u16),
     IND(Sim8051::ScratchpadRegisters),
-    BIT_ADDR(Sim8051::SFR, u8),
+    BIT_ADDR(u8), // address in the 00H - FFH bit space
     LABEL(String),
     INVALID,
     NULL,
 }
 
+// Directives that define a symbol, written as   name directive value
+pub const SYMBOL_DIRECTIVES: [&str; 7] = ["equ", "set", "bit", "data", "idata", "xdata", "code"];
+
 // It will only return the token for now .. More thing to be done on the parser side from here
 #[derive(Debug)]
 pub struct Token {
@@ -25,65 +29,57 @@ pub struct Token {
     pub len: usize,
 }
 
+// A single source line broken down into its parts .. used by the two pass assembler
+#[derive(Debug, Default)]
+pub struct Statement {
+    pub label: Option<String>,
+    pub name: Option<String>, // symbol defined by EQU, SET, BIT, DATA .. which is written without a colon
+    pub mnemonic: Option<String>,
+    pub operands: Vec<String>,
+}
+
+#[derive(Default)]
 pub struct Tokenizer {
     pub src: String,
     pub pos: usize,
 }
 
-impl Default for Tokenizer {
-    fn default() -> Tokenizer {
-        Tokenizer {
-            src: String::new(),
-            pos: 0,
-        }
-    }
-}
-
 impl Tokenizer {
     pub fn parse_all(lexeme: &str) -> Option<Token> {
-        let mut buf = String::with_capacity(50);
         // TODO :: If newline, return newline token or maybe not..
         // Now we going for parser and parsing
-        let mut ptr = lexeme
-            .chars()
-            .skip_while(|x| x.is_ascii_whitespace())
-            .peekable();
-        let mut token = None;
-        // Consume white space character here
-        token = match ptr.peek() {
+        // Consume white space character here, so that the prefix characters can be sliced off below
+        let lexeme = lexeme.trim_start();
+        let mut ptr = lexeme.chars().peekable();
+        let token = match ptr.peek() {
             None => None,
             Some(y) => {
                 match *y {
                     ch if ch.is_ascii_digit() => {
-                        if let Some(hex) = Self::parse_hex(&lexeme) {
-                            Some(Token {
-                                token: TokenType::HEX(hex),
-                                len: 0,
+                        let word = Self::number_word(lexeme);
+                        Self::parse_bitaddr(lexeme).or_else(|| {
+                            Self::parse_number(&word).ok().map(|num| Token {
+                                token: TokenType::HEX(num),
+                                len: word.len(),
                             })
-                        } else {
-                            None
-                        }
+                        })
                     }
                     '#' => {
                         // This is the immediate operands
-                        if let Some(hex) = Self::parse_hex(&lexeme[1..]) {
-                            Some(Token {
-                                token: TokenType::IMM(hex),
-                                len: 0,
-                            })
-                        } else {
-                            None
-                        }
+                        let word = Self::number_word(&lexeme[1..]);
+                        Self::parse_number(&word).ok().map(|num| Token {
+                            token: TokenType::IMM(num),
+                            len: word.len() + 1,
+                        })
                     }
                     '@' => {
                         use std::str::FromStr;
-                        Some(Token {
-                            token: TokenType::IND(
-                                Sim8051::ScratchpadRegisters::from_str(&lexeme[1..])
-                                    .expect("Failed to match indirectable register"),
-                            ),
-                            len: 2,
-                        })
+                        Sim8051::ScratchpadRegisters::from_str(&lexeme[1..])
+                            .ok()
+                            .map(|reg| Token {
+                                token: TokenType::IND(reg),
+                                len: 3,
+                            })
                     }
                     ',' => Some(Token {
                         token: TokenType::COMMA,
@@ -95,10 +91,10 @@ impl Tokenizer {
                     }), // Maybe we could ignore these white spaces / newlines for pasrsing
                     _ => {
                         // First try parsing as bit field and then only fallback to id
-                        let is_bitaddr = Self::parse_bitaddr(&lexeme);
+                        let is_bitaddr = Self::parse_bitaddr(lexeme);
                         match is_bitaddr {
                             Some(_) => is_bitaddr,
-                            None => Self::parse_id(&lexeme),
+                            None => Self::parse_id(lexeme),
                         }
                     }
                 }
@@ -106,10 +102,11 @@ impl Tokenizer {
         };
         token
     }
-    pub fn read_file(&mut self, src: String) {
-        let mut file = std::fs::File::open(src).expect("Cannot open input src file ");
-        file.read_to_string(&mut self.src)
-            .expect("Failed to read input file");
+    pub fn read_file(&mut self, src: String) -> bool {
+        match std::fs::File::open(src) {
+            Ok(mut file) => file.read_to_string(&mut self.src).is_ok(),
+            Err(_) => false,
+        }
     }
 
     pub fn parse_hex(lexeme: &str) -> Option<u16> {
@@ -117,7 +114,6 @@ impl Tokenizer {
 
         let mut ptr = lexeme.chars().peekable();
         let mut token = None;
-        let mut count = 0;
         loop {
             match ptr.peek() {
                 None => {
@@ -127,7 +123,8 @@ impl Tokenizer {
                 Some(y) => match *y {
                     ch @ '0'..='9' => num = (num << 4) | (ch as u16 - '0' as u16),
                     ch @ 'A'..='F' => num = (num << 4) | (ch as u16 - 'A' as u16 + 10),
-                    'H' => {
+                    ch @ 'a'..='f' => num = (num << 4) | (ch as u16 - 'a' as u16 + 10),
+                    'H' | 'h' => {
                         token = Some(num);
                         break;
                     }
@@ -137,12 +134,54 @@ impl Tokenizer {
                     }
                 },
             }
-            count += 1;
             ptr.next();
         }
         token
     }
 
+    // Numeric literal in any of the usual forms .. 0x3F, 3FH, 0FFh, 01010101B, 17Q, 17O, 10D and plain 10
+    // Hex with the H suffix has to start with a digit, otherwise it would be a name like FFH
+    pub fn parse_number(word: &str) -> Result<u16, String> {
+        let invalid = || format!("Invalid number {}", word);
+        if !word.starts_with(|x: char| x.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let (digits, radix) = match word.strip_prefix("0x").or(word.strip_prefix("0X")) {
+            Some(digits) => (digits, 16),
+            None => {
+                let body = word.char_indices().last().map_or("", |(i, _)| &word[..i]);
+                match word.chars().last() {
+                    Some('H' | 'h') => (body, 16),
+                    Some('B' | 'b') => (body, 2),
+                    Some('Q' | 'q' | 'O' | 'o') => (body, 8),
+                    Some('D' | 'd') => (body, 10),
+                    _ => (word, 10),
+                }
+            }
+        };
+        if digits.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut num: u32 = 0;
+        for ch in digits.chars() {
+            let digit = ch.to_digit(radix).ok_or_else(invalid)?;
+            num = num * radix + digit;
+            if num > 0xFFFF {
+                return Err(format!("Number {} does not fit in 16 bits", word));
+            }
+        }
+        Ok(num as u16)
+    }
+
+    // Digits and radix letters of a number at the start of the lexeme
+    fn number_word(lexeme: &str) -> String {
+        lexeme
+            .chars()
+            .take_while(|x| x.is_ascii_alphanumeric())
+            .collect()
+    }
+
     pub fn parse_all_as_id(&self) -> Option<Token> {
         let count = self
             .src
@@ -151,34 +190,33 @@ impl Tokenizer {
             .take_while(|x| x.is_ascii_whitespace())
             .count();
 
-        let mut len = 0;
         let mut ptr = self.src.chars().skip(self.pos + count).peekable();
 
-        let specials = vec!['.', '@', '#', ':'];
+        let specials = ['.', '@', '#', ':'];
         if let Some(y) = ptr.peek() {
-            if !(y.is_ascii_alphanumeric() || specials.contains(&y)) {
+            if !(y.is_ascii_alphanumeric() || specials.contains(y)) {
                 return None;
             }
         } else {
             return None;
         }
-        let done = ptr.take_while(|x| x.is_ascii_alphanumeric() || specials.contains(&x));
+        let done = ptr.take_while(|x| x.is_ascii_alphanumeric() || specials.contains(x));
         let buf: String = done.collect();
 
-        len = buf.len() + count;
+        let len = buf.len() + count;
 
-        return Some(Token {
+        Some(Token {
             token: TokenType::ID(buf),
             len,
-        });
+        })
     }
 
     // try parsing as id first
     pub fn parse_id(lexeme: &str) -> Option<Token> {
-        // First letter should be alphabetic
+        // First letter should be alphabetic, underscores are allowed for label names
         let mut ptr = lexeme.chars().peekable();
         if let Some(y) = ptr.peek() {
-            if !y.is_ascii_alphabetic() {
+            if !(y.is_ascii_alphabetic() || *y == '_') {
                 return None;
             }
         } else {
@@ -186,20 +224,19 @@ impl Tokenizer {
         }
 
         let mut len = 0;
-        let done = ptr.take_while(|x| x.is_ascii_alphanumeric());
+        let done = ptr.take_while(|x| x.is_ascii_alphanumeric() || *x == '_');
         let buf: String = done.collect();
         len += buf.len();
-        return Some(Token {
+        Some(Token {
             token: TokenType::ID(buf),
             len,
-        });
+        })
     }
 
     pub fn parse_label(lexeme: &str) -> Option<Token> {
-        let mut str = Self::parse_id(lexeme);
-        let mut token: Option<Token> = None;
+        let str = Self::parse_id(lexeme);
         // Rewrite
-        token = match str {
+        let token = match str {
             None => None,
             Some(tok) => match tok.token {
                 TokenType::ID(mut id) => {
@@ -227,52 +264,36 @@ impl Tokenizer {
 
     pub fn parse_bitaddr(lexeme: &str) -> Option<Token> {
         // Its syntax is something followed by dot and then followed by a single number .. Nothing more
+        // Something is either a bit addressable SFR or the address of a bit addressable byte .. ACC.7 or 20H.3
         use std::str::FromStr;
-        let addressable = Self::parse_id(lexeme);
-        let mut token = None;
-        token = match addressable {
-            None => None,
-            Some(tok) => match tok.token {
-                TokenType::ID(str) => {
-                    let mut ptr = lexeme.chars().skip(tok.len).peekable();
-                    match ptr.peek() {
-                        None => None,
-                        Some(z) => {
-                            if *z == '.' {
-                                ptr.next();
-                                // Continue parsing toward a number
-                                if let Some(ch) = ptr.next() {
-                                    println!("Values are {} and {}.", ch as u8, '0' as u8);
-                                    let var = ch as u8 - '0' as u8;
-                                    if var < 10 {
-                                        Some(Token {
-                                            token: TokenType::BIT_ADDR(
-                                                Sim8051::SFR::from_str(&str)
-                                                    .expect("Not a bitaddressable variable"),
-                                                var,
-                                            ),
-                                            len: tok.len + 2,
-                                        })
-                                    } else {
-                                        None
-                                    }
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        }
-                    }
-                }
-                _ => None,
-            },
+        let (byte, len) = if lexeme.starts_with(|x: char| x.is_ascii_digit()) {
+            let word = Self::number_word(lexeme);
+            let byte = u8::try_from(Self::parse_number(&word).ok()?).ok()?;
+            (byte, word.len())
+        } else {
+            let tok = Self::parse_id(lexeme)?;
+            let name = match tok.token {
+                TokenType::ID(name) => name,
+                _ => return None,
+            };
+            let sfr = Sim8051::SFR::from_str(&name)
+                .ok()
+                .filter(Sim8051::is_bit_addressable)?;
+            (Sim8051::sfr_addr(&sfr), tok.len)
         };
-        token
+        let mut ptr = lexeme.chars().skip(len);
+        if ptr.next() != Some('.') {
+            return None;
+        }
+        let bit = ptr.next()?.to_digit(8)? as u8;
+        Some(Token {
+            token: TokenType::BIT_ADDR(Sim8051::bit_address(byte, bit)?),
+            len: len + 2,
+        })
     }
 
     pub fn consume_comma(&mut self) -> bool {
-        let ptr = self.src.chars().skip(self.pos).next();
+        let ptr = self.src.chars().nth(self.pos);
         match ptr {
             None => false, // throw some kind of error here
             Some(ch) => {
@@ -288,11 +309,114 @@ impl Tokenizer {
     pub fn consume_newlines(&mut self) -> bool {
         let ptr = self.src.chars().skip(self.pos);
         let count = ptr.take_while(|x| x.is_ascii_whitespace()).count();
-        self.pos = self.pos + count;
-        match count {
-            0 => false,
-            _ => true,
+        self.pos += count;
+        count != 0
+    }
+
+    // Splits a line as   label: mnemonic op1, op2, op3   ; comment
+    //                or   name directive value     for the symbol defining directives
+    // Mnemonics and directives come out in lowercase, register operands in uppercase, so that source can use any case
+    // Operands are returned as raw strings and classified later using parse_all
+    pub fn parse_statement(line: &str) -> Statement {
+        let mut stmt = Statement::default();
+        let mut rest = Self::strip_comment(line).trim();
+
+        if let Some(tok) = Self::parse_label(rest) {
+            if let TokenType::LABEL(label) = tok.token {
+                stmt.label = Some(label.trim_end_matches(':').to_string());
+                rest = rest[tok.len..].trim_start();
+            }
+        }
+
+        let mut mnemonic = Self::first_word(rest);
+        if mnemonic.is_empty() {
+            return stmt;
         }
+        rest = rest[mnemonic.len()..].trim();
+
+        if stmt.label.is_none() {
+            let directive = Self::first_word(rest);
+            if SYMBOL_DIRECTIVES.contains(&directive.to_ascii_lowercase().as_str()) {
+                stmt.name = Some(mnemonic);
+                rest = rest[directive.len()..].trim();
+                mnemonic = directive;
+            }
+        }
+        stmt.mnemonic = Some(mnemonic.to_ascii_lowercase());
+        stmt.operands = Self::split_operands(rest)
+            .into_iter()
+            .map(|x| Self::canonical_operand(&x).unwrap_or(x))
+            .collect();
+        stmt
+    }
+
+    // Everything after ; is a comment, unless the ; is inside quotes as in DB ';'
+    pub fn strip_comment(line: &str) -> &str {
+        let mut quote = None;
+        for (i, ch) in line.char_indices() {
+            match (quote, ch) {
+                (None, '\'' | '"') => quote = Some(ch),
+                (Some(q), _) if q == ch => quote = None,
+                (None, ';') => return &line[..i],
+                _ => {}
+            }
+        }
+        line
+    }
+
+    // Uppercase form of the operands that name a register, like a, dptr, r0 or @a+dptr
+    // Everything else, the symbols included, is left as written
+    fn canonical_operand(op: &str) -> Option<String> {
+        let upper: String = op
+            .chars()
+            .filter(|x| !x.is_ascii_whitespace())
+            .collect::<String>()
+            .to_ascii_uppercase();
+        let reserved = [
+            "A", "C", "AB", "DPTR", "PC", "@DPTR", "@A+DPTR", "@A+PC", "@R0", "@R1",
+        ];
+        let register = upper.len() == 2
+            && upper.starts_with('R')
+            && matches!(upper.as_bytes()[1], b'0'..=b'7');
+        if reserved.contains(&upper.as_str()) || register {
+            Some(upper)
+        } else {
+            None
+        }
+    }
+
+    fn first_word(src: &str) -> String {
+        src.chars()
+            .take_while(|x| !x.is_ascii_whitespace())
+            .collect()
+    }
+
+    // Commas inside quoted strings, as in DB 'a,b', don't separate operands
+    fn split_operands(src: &str) -> Vec<String> {
+        let mut operands = Vec::new();
+        let mut current = String::new();
+        let mut quote = None;
+        for ch in src.chars() {
+            match (quote, ch) {
+                (None, '\'' | '"') => {
+                    quote = Some(ch);
+                    current.push(ch);
+                }
+                (Some(q), _) if q == ch => {
+                    quote = None;
+                    current.push(ch);
+                }
+                (None, ',') => operands.push(std::mem::take(&mut current)),
+                _ => current.push(ch),
+            }
+        }
+        operands.push(current);
+        // Only the spaces around an operand are dropped, quoted strings keep theirs
+        operands
+            .into_iter()
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect()
     }
 }
 
@@ -301,14 +425,13 @@ pub fn nothing() {
 }
 
 pub fn string_handling() {
-    let mut str = String::new();
-    str = String::from("Hello from The Rust Programming Language");
+    let mut str = String::from("Hello from The Rust Programming Language");
     unsafe {
         for ch in str.as_bytes_mut().iter_mut() {
             *ch = ch.to_ascii_uppercase();
         }
     }
-    let z = vec![1, 2, 3, 4];
+    let z = [1, 2, 3, 4];
     let mut it = z.iter().peekable();
     loop {
         let val = it.next();
@@ -387,7 +510,7 @@ pub fn string_handling() {
     //     Some(z) => println!("Found some token :-> {:?}.", z),
     // }
 
-    // let f = TokenType::BIT_ADDR(Sim8051::SFR::Port(Sim8051::Ports::P0),3);
+    // let f = TokenType::BIT_ADDR(0x83);
     // println!("In Debug format {:?}.",f);
     let mut test = Tokenizer {
         src: String::from("label1:   mov @R0, #22H \n mov P0.1, #34H"),
@@ -418,34 +541,8 @@ pub fn string_handling() {
 
     println!("\n\nParsing now : ");
     let str = "P0jpt";
-    match Tokenizer::parse_all(&str) {
+    match Tokenizer::parse_all(str) {
         None => println!("No such bit addr to be found"),
         Some(z) => println!("Found bit addr -> {:?}.", z),
     }
 }
-
-pub fn retrieve_rvalue(sim: &mut Sim8051::Sim8051, token: &TokenType) -> Option<u8> {
-    use TokenType::*;
-    match &token {
-        HEX(hex) => Some(sim.internal_memory.memory[*hex as usize]),
-        IMM(hex) => Some(*hex as u8),
-        ID(reg) => {
-            use std::str::FromStr;
-            let reg = Sim8051::ScratchpadRegisters::from_str(reg.as_str())
-                .expect("Not a scratchpad register.. error");
-            // Return its location depending upon the currently selected register bank
-            let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(Sim8051::IRegs::PSW)) as usize;
-            let count = (0x18 & sim.internal_memory.memory[pswloc]) >> 3;
-            let start = (count * 8) as usize;
-            Some(sim.internal_memory.memory[start + reg.reg_count() as usize])
-        }
-        // For indirect addressing, retrieve the value of the register to use as src location
-        IND(reg) => {
-            let pswloc = Sim8051::sfr_addr(&Sim8051::SFR::Reg(Sim8051::IRegs::PSW)) as usize;
-            let count = (0x18 & sim.internal_memory.memory[pswloc]) >> 3;
-            let val = count * 8 + reg.reg_count();
-            Some(sim.internal_memory.memory[sim.internal_memory.memory[val as usize] as usize])
-        }
-        _ => None,
-    }
-}
@@ -1,8 +1,18 @@
 use std::os::raw::c_char;
 
+#[allow(non_snake_case)]
 pub mod Sim8051;
 pub mod assembler;
+pub mod diagnostic;
+pub mod expr;
+pub mod ihex;
+pub mod instruction;
+pub mod interrupt;
 pub mod lexer;
+pub mod port;
+pub mod serial;
+pub mod timer;
+pub mod xdata;
 // Disable the name mangling
 
 // Define a struct to out all the required information
@@ -11,7 +21,7 @@ pub mod lexer;
 // 128 byte memory address, let's pass whole thing
 // Scratchpad registers
 // Ports
-// Errors .. which we will be covering later
+// Errors and warnings of the assembler
 // Lets get done with it, there's no point on taking this assembler ahead further .. Better work on a new compiler including both complete front-end and back-end
 
 #[repr(C)]
@@ -24,6 +34,8 @@ pub struct AsmData {
     reg_len: u64,
     memory: *mut u8,
     memory_len: u64, // Every other thing can be inferred from here in C++ side
+    errors: *mut u8, // diagnostics as UTF-8 text, one per line .. empty when compiled cleanly
+    errors_len: u64,
 }
 
 impl Default for AsmData {
@@ -37,6 +49,8 @@ impl Default for AsmData {
             reg_len: 0,
             memory: std::ptr::null_mut(),
             memory_len: 0,
+            errors: std::ptr::null_mut(),
+            errors_len: 0,
         }
     }
 }
@@ -44,39 +58,52 @@ impl Default for AsmData {
 #[no_mangle]
 // its return type would be a struct that pass every information of the assembler to c++
 pub extern "C" fn RustAssemble(ptr: *const c_char, len: u64) -> AsmData {
-    let compiled = false;
     if ptr.is_null() {
-        let mut failed_data = AsmData::default();
-        failed_data.compiled = false;
-        return failed_data;
+        return AsmData::default();
     }
 
     println!("Rust got the length : {}.", len);
-    let src = unsafe {
-        std::str::from_utf8_unchecked(std::slice::from_raw_parts(ptr as *const u8, len as usize))
-    };
+    // Editor content may not be valid UTF-8, the odd characters are replaced rather than trusted
+    let src = unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    let src = String::from_utf8_lossy(src).into_owned();
+
+    // A panic must never unwind into the Qt host, it is turned into an error for the editor instead
+    match std::panic::catch_unwind(|| assemble_for_host(src)) {
+        Ok(ffi_data) => ffi_data,
+        Err(_) => {
+            let mut ffi_data = AsmData::default();
+            set_errors(&mut ffi_data, "error: internal assembler error\n");
+            ffi_data
+        }
+    }
+}
 
-    let mut asm = assembler::Assembler::default();
+fn set_errors(ffi_data: &mut AsmData, text: &str) {
+    let errors = text.as_bytes().to_vec().into_boxed_slice();
+    ffi_data.errors_len = errors.len() as u64;
+    ffi_data.errors = std::boxed::Box::into_raw(errors) as *mut _;
+}
 
-    asm.read_src_from_string(String::from(src));
+fn assemble_for_host(src: String) -> AsmData {
+    let mut asm = assembler::Assembler::default();
 
-    asm.tokenizer.src.push_str("\n$");
-    let z = asm.tokenizer.src;
-    asm.tokenizer.src = String::from("^\n");
-    asm.tokenizer.src.push_str(&z);
+    asm.read_src_from_string(src);
 
     println!("\nRead asm src file : \n {}", asm.tokenizer.src);
 
-    for i in &mut asm.simulator.internal_memory.memory {
-        *i = 0x00;
-    }
+    asm.simulator.power_on(Sim8051::PowerOnRam::Fill(0x00));
 
-    asm.start();
+    let compiled = asm.start();
+    let mut errors = String::new();
+    for diagnostic in &asm.diagnostics {
+        println!("{}", diagnostic);
+        errors.push_str(&format!("{}\n", diagnostic));
+    }
     println!("------------------------- Showing 8051 Flags Status -----------------------------");
     asm.simulator.show_flags();
     asm.simulator.show_scratchpad_registers();
     asm.simulator.show_sfr_registers();
-    asm.show_jmptable();
+    asm.show_symbols();
 
     //    return AsmData::default();
     let mut ffi_data = AsmData::default();
@@ -96,7 +123,7 @@ pub extern "C" fn RustAssemble(ptr: *const c_char, len: u64) -> AsmData {
     use Sim8051::Ports::*;
     use Sim8051::SFR::*;
 
-    let vec = vec![
+    let vec = [
         Reg(PSW),
         Reg(ACC),
         Reg(B),
@@ -107,11 +134,12 @@ pub extern "C" fn RustAssemble(ptr: *const c_char, len: u64) -> AsmData {
     ];
     let sfr_vec: Vec<u8> = vec
         .iter()
-        .map(|reg| asm.simulator.internal_memory.memory[Sim8051::sfr_addr(&reg) as usize])
+        .map(|reg| asm.simulator.internal_memory.read_sfr(reg))
         .collect();
     ffi_data.sfr_len = sfr_vec.len() as u64;
     ffi_data.sfr_arr = std::boxed::Box::into_raw(sfr_vec.into_boxed_slice()) as *mut _;
-    ffi_data.compiled = true;
+    ffi_data.compiled = compiled;
+    set_errors(&mut ffi_data, &errors);
 
     ffi_data
 }
@@ -1,8 +1,18 @@
 // Lets start our 8051 Simulator here
 // First need to learn some 8051 first
+#[allow(non_snake_case)]
 pub mod Sim8051;
 pub mod assembler;
+pub mod diagnostic;
+pub mod expr;
+pub mod ihex;
+pub mod instruction;
+pub mod interrupt;
 pub mod lexer;
+pub mod port;
+pub mod serial;
+pub mod timer;
+pub mod xdata;
 
 fn main() {
     println!("Hello 8051 EmuSimulator");
@@ -17,21 +27,46 @@ fn main() {
     //     *i = 0xAB;
     // }
 
-    asm.read_src(String::from("./test.asm"));
+    // A prebuilt .hex/.ihx or .bin image can be given instead of the test source, and is run from 0000H
+    let image = std::env::args().nth(1);
 
-    asm.tokenizer.src.push_str("\n$");
-    let z = asm.tokenizer.src;
-    asm.tokenizer.src = String::from("^\n");
-    asm.tokenizer.src.push_str(&z);
-    println!("\nRead asm src file : \n {}", asm.tokenizer.src);
+    asm.simulator.power_on(Sim8051::PowerOnRam::Fill(0x00));
+    // Whatever the program sends over the serial port shows up here
+    asm.simulator.serial.echo = true;
 
-    // Now start parsing the grammar
+    match image {
+        Some(image) => {
+            let loaded = if image.ends_with(".bin") {
+                asm.simulator.load_bin(image.clone(), 0x0000)
+            } else {
+                asm.simulator.load_hex(image.clone(), 0x0000)
+            };
+            match loaded {
+                Ok(end) => {
+                    println!("\nLoaded {} up to {:04X}H", image, end);
+                    asm.simulator.set_pc(0x0000);
+                    if !asm.simulator.run_until(end, assembler::STEP_LIMIT) {
+                        println!(
+                            "Program did not finish within {} instructions",
+                            assembler::STEP_LIMIT
+                        );
+                    }
+                }
+                Err(err) => println!("\nFailed to load {} : {}", image, err),
+            }
+        }
+        None => {
+            asm.read_src(String::from("./test.asm"));
 
-    for i in &mut asm.simulator.internal_memory.memory {
-        *i = 0x00;
-    }
+            println!("\nRead asm src file : \n {}", asm.tokenizer.src);
 
-    asm.start();
+            // Now assemble and run it
+            asm.start();
+            for diagnostic in &asm.diagnostics {
+                println!("{}", diagnostic);
+            }
+        }
+    }
 
     println!("\n\nAfter executing the source code : ");
     for (n, i) in asm
@@ -39,7 +74,6 @@ fn main() {
         .internal_memory
         .memory
         .iter()
-        .skip(0)
         .take(16)
         .enumerate()
     {
@@ -53,5 +87,5 @@ fn main() {
     asm.simulator.show_flags();
     asm.simulator.show_scratchpad_registers();
     asm.simulator.show_sfr_registers();
-    asm.show_jmptable();
+    asm.show_symbols();
 }
@@ -0,0 +1,64 @@
+// Port pins of P0 - P3, kept apart from the port latches in the SFRs
+//
+// Writing a port always goes to the latch, what the pin shows depends on the latch and whatever is attached outside
+//  latch 0 -> the pin is pulled low hard, nothing outside gets it back high
+//  latch 1 -> P1 - P3 are weakly pulled high, so an external driver decides the level when there's one
+//             P0 is open drain and floats, it only reads high with external pull ups or a driver forcing it high
+//             floating pins read low here, real silicon gives whatever is left on the pin
+//
+// Read-modify-write instructions (ANL, ORL, XRL, INC, DEC, DJNZ, CPL, JBC, and the bit writes) work on the latch,
+// every other read of a port sees the pins
+
+use crate::Sim8051::Sim8051;
+
+// What the outside world does to each pin, indexed by port * 8 + bit
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PortPins {
+    drivers: [Option<bool>; 32],
+    // Resistor network on P0, as needed when it's used for I/O
+    pub p0_pullups: bool,
+}
+
+// Port and pin number of a bit address that falls on P0 - P3
+fn port_pin(bit: u8) -> Option<(usize, usize)> {
+    match bit & 0xF8 {
+        0x80 | 0x90 | 0xA0 | 0xB0 => Some(((bit as usize >> 4) - 8, bit as usize & 0x07)),
+        _ => None,
+    }
+}
+
+impl Sim8051 {
+    // Forces the pin given by its bit address (bits::INT0, 0x90 for P1.0 ..) high or low until it's released
+    pub fn drive_pin(&mut self, bit: u8, level: bool) {
+        if let Some((port, n)) = port_pin(bit) {
+            self.port_pins.drivers[port * 8 + n] = Some(level);
+        }
+    }
+
+    pub fn release_pin(&mut self, bit: u8) {
+        if let Some((port, n)) = port_pin(bit) {
+            self.port_pins.drivers[port * 8 + n] = None;
+        }
+    }
+
+    // Level of a port pin given by its bit address, any other bit just reads as it is
+    pub fn pin(&self, bit: u8) -> bool {
+        let latch = self.read_bit(bit);
+        let Some((port, n)) = port_pin(bit) else {
+            return latch;
+        };
+        match self.port_pins.drivers[port * 8 + n] {
+            _ if !latch => false,
+            Some(level) => level,
+            None => port != 0 || self.port_pins.p0_pullups,
+        }
+    }
+
+    // What a non read-modify-write instruction sees at a direct address, the pins of a port or the register otherwise
+    pub fn read_port(&self, addr: u8) -> u8 {
+        if !matches!(addr, 0x80 | 0x90 | 0xA0 | 0xB0) {
+            return self.read_direct(addr);
+        }
+        (0..8).fold(0, |byte, n| byte | ((self.pin(addr + n) as u8) << n))
+    }
+}
@@ -0,0 +1,300 @@
+// Serial port of the 8051, clocked once every machine cycle alongside the timers
+//
+// SCON    SM0 SM1 SM2 REN TB8 RB8 TI RI
+//  mode 0 -> shift register, 8 bits at fosc / 12
+//  mode 1 -> 8 bit UART, start + 8 data + stop, baud from timer 1 or timer 2
+//  mode 2 -> 9 bit UART, start + 8 data + TB8/RB8 + stop, baud fosc / 64 or fosc / 32 with SMOD
+//  mode 3 -> 9 bit UART with the baud of mode 1
+//
+// Timer 1 overflows are divided by 32, or by 16 with SMOD (PCON.7), while timer 2 in baud rate generator mode is
+// always divided by 16 .. TCLK and RCLK pick timer 2 for the transmitter and the receiver separately
+//
+// SBUF is two registers behind one address, writes go to the transmitter and reads see the receive buffer kept in the SFR
+// With SM2 set, modes 2 and 3 only take frames whose 9th bit is 1 and mode 1 only frames with a valid stop bit
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+use crate::Sim8051::{bits, sfr_addr, IRegs, Sim8051, SFR};
+
+// A frame being shifted in or out, bits counts the bit times left until it's done
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    data: u8,
+    ninth: bool,
+    bits: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct Serial {
+    tx: Option<Frame>,
+    rx: Option<Frame>,
+    // Baud clock counts towards the next bit time
+    tx_clock: u16,
+    rx_clock: u16,
+    // Host side of the line, bytes the program sent and bytes waiting to be received with their 9th bit
+    output: Vec<u8>,
+    input: VecDeque<(u8, bool)>,
+    // Transmitted bytes are also printed to stdout
+    pub echo: bool,
+}
+
+impl Serial {
+    // Frames on the line are dropped, the host side is kept
+    pub fn reset(&mut self) {
+        self.tx = None;
+        self.rx = None;
+        self.tx_clock = 0;
+        self.rx_clock = 0;
+    }
+}
+
+impl Sim8051 {
+    // Queues a byte for the receiver, the 9th bit of modes 2 and 3 is 1 so SM2 lets it through
+    pub fn serial_inject(&mut self, byte: u8) {
+        self.serial_inject9(byte, true);
+    }
+
+    pub fn serial_inject9(&mut self, byte: u8, ninth: bool) {
+        self.serial.input.push_back((byte, ninth));
+    }
+
+    pub fn serial_inject_str(&mut self, text: &str) {
+        for byte in text.bytes() {
+            self.serial_inject(byte);
+        }
+    }
+
+    // Bytes transmitted so far
+    pub fn serial_output(&self) -> &[u8] {
+        &self.serial.output
+    }
+
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.serial.output)
+    }
+
+    // Called on a write to SBUF, starts shifting out a frame and drops any one in progress
+    pub(crate) fn serial_transmit(&mut self, data: u8) {
+        let mode = self.serial_mode();
+        self.serial.tx = Some(Frame {
+            data,
+            ninth: self.read_bit(bits::TB8),
+            bits: frame_bits(mode),
+        });
+    }
+
+    // One machine cycle of the serial port, with whether timer 1 overflowed and how often timer 2 did
+    pub fn tick_serial(&mut self, t1_overflow: bool, t2_overflows: u8) {
+        let mode = self.serial_mode();
+        let t2con = self.read_direct(sfr_addr(&SFR::Reg(IRegs::T2CON)));
+        let smod = self.read_direct(sfr_addr(&SFR::Reg(IRegs::PCON))) & 0x80 != 0;
+
+        let (tclk, rclk) = (t2con & 0x10 != 0, t2con & 0x20 != 0);
+        let tx_bits = self.baud_clock(mode, smod, tclk, t1_overflow, t2_overflows, true);
+        let rx_bits = self.baud_clock(mode, smod, rclk, t1_overflow, t2_overflows, false);
+        self.shift_out(tx_bits);
+        self.shift_in(mode, rx_bits);
+    }
+
+    fn serial_mode(&self) -> u8 {
+        (self.read_direct(sfr_addr(&SFR::Reg(IRegs::SCON))) >> 6) & 0x03
+    }
+
+    // Bit times passed this machine cycle for the transmitter or the receiver
+    fn baud_clock(&mut self, mode: u8, smod: bool, timer2: bool, t1: bool, t2: u8, tx: bool) -> u8 {
+        // (clock increment, clock counts per bit)
+        let (count, period) = match mode {
+            0 => return 1,
+            // 12 oscillator periods pass every machine cycle
+            2 => (12, if smod { 32 } else { 64 }),
+            _ if timer2 => (t2 as u16, 16),
+            _ => (t1 as u16, if smod { 16 } else { 32 }),
+        };
+        let clock = if tx {
+            &mut self.serial.tx_clock
+        } else {
+            &mut self.serial.rx_clock
+        };
+        *clock += count;
+        let mut passed = 0;
+        while *clock >= period {
+            *clock -= period;
+            passed += 1;
+        }
+        passed
+    }
+
+    fn shift_out(&mut self, bit_times: u8) {
+        let Some(mut frame) = self.serial.tx else {
+            return;
+        };
+        frame.bits = frame.bits.saturating_sub(bit_times);
+        if frame.bits > 0 {
+            self.serial.tx = Some(frame);
+            return;
+        }
+        self.serial.tx = None;
+        self.serial.output.push(frame.data);
+        if self.serial.echo {
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(&[frame.data]);
+            let _ = stdout.flush();
+        }
+        self.write_bit(bits::TI, true);
+    }
+
+    fn shift_in(&mut self, mode: u8, bit_times: u8) {
+        let frame = match self.serial.rx {
+            Some(frame) => frame,
+            // Mode 0 only receives with RI cleared, the UART modes as soon as a frame comes in
+            None if self.read_bit(bits::REN) && (mode != 0 || !self.read_bit(bits::RI)) => {
+                let Some((data, ninth)) = self.serial.input.pop_front() else {
+                    return;
+                };
+                Frame {
+                    data,
+                    ninth,
+                    bits: frame_bits(mode),
+                }
+            }
+            None => return,
+        };
+        let bits_left = frame.bits.saturating_sub(bit_times);
+        if bits_left > 0 {
+            self.serial.rx = Some(Frame {
+                bits: bits_left,
+                ..frame
+            });
+            return;
+        }
+        self.serial.rx = None;
+
+        // RB8 gets the 9th bit in modes 2 and 3 and the stop bit in mode 1
+        let ninth = match mode {
+            0 => false,
+            1 => true,
+            _ => frame.ninth,
+        };
+        // A frame that arrives while RI is still set is lost
+        if self.read_bit(bits::RI) || (mode != 0 && self.read_bit(bits::SM2) && !ninth) {
+            return;
+        }
+        // Straight into the SFR, a write through write_direct would be a transmit
+        self.internal_memory
+            .write_sfr(&SFR::Reg(IRegs::SBUF), frame.data);
+        if mode != 0 {
+            self.write_bit(bits::RB8, ninth);
+        }
+        self.write_bit(bits::RI, true);
+    }
+}
+
+// Bit times of a whole frame, start and stop bits included
+fn frame_bits(mode: u8) -> u8 {
+    match mode {
+        0 => 8,
+        1 => 10,
+        _ => 11,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(sim: &mut Sim8051, reg: IRegs, val: u8) {
+        sim.write_direct(sfr_addr(&SFR::Reg(reg)), val);
+    }
+
+    // Peripherals ticked the same way the core does
+    fn machine_cycle(sim: &mut Sim8051) {
+        let t1_overflow = sim.tick_timers();
+        let t2_overflows = sim.tick_timer2();
+        sim.tick_serial(t1_overflow, t2_overflows);
+    }
+
+    // Machine cycles until the bit gets set
+    fn cycles_until(sim: &mut Sim8051, bit: u8, limit: usize) -> Option<usize> {
+        (1..=limit).find(|_| {
+            machine_cycle(sim);
+            sim.read_bit(bit)
+        })
+    }
+
+    // Timer 1 in mode 2 reloading FDH, 9600 baud at 11.0592 MHz
+    fn timer1_baud(smod: bool) -> Sim8051 {
+        let mut sim = Sim8051::default();
+        set(&mut sim, IRegs::TMOD, 0x20);
+        set(&mut sim, IRegs::TH1, 0xFD);
+        set(&mut sim, IRegs::TL1, 0xFD);
+        set(&mut sim, IRegs::PCON, if smod { 0x80 } else { 0x00 });
+        sim.write_bit(bits::TR1, true);
+        sim
+    }
+
+    #[test]
+    fn mode1_frame_takes_10_bit_times_of_timer1() {
+        for (smod, expected) in [(false, 960), (true, 480)] {
+            let mut sim = timer1_baud(smod);
+            set(&mut sim, IRegs::SCON, 0x40);
+            set(&mut sim, IRegs::SBUF, b'U');
+            let cycles = cycles_until(&mut sim, bits::TI, 2000).unwrap();
+            assert!(cycles.abs_diff(expected) <= 6, "{} cycles", cycles);
+            assert_eq!(sim.serial_output(), b"U");
+        }
+    }
+
+    #[test]
+    fn tclk_and_rclk_pick_timer2() {
+        // Timer 2 overflows every machine cycle, timer 1 is stopped
+        let mut sim = Sim8051::default();
+        set(&mut sim, IRegs::SCON, 0x50);
+        for reg in [IRegs::RCAP2L, IRegs::TL2] {
+            set(&mut sim, reg, 0xFA);
+        }
+        for reg in [IRegs::RCAP2H, IRegs::TH2] {
+            set(&mut sim, reg, 0xFF);
+        }
+        set(&mut sim, IRegs::T2CON, 0x14);
+        set(&mut sim, IRegs::SBUF, b'A');
+        assert_eq!(cycles_until(&mut sim, bits::TI, 2000), Some(160));
+
+        sim.serial_inject(b'B');
+        assert_eq!(cycles_until(&mut sim, bits::RI, 2000), None);
+        set(&mut sim, IRegs::T2CON, 0x34);
+        assert!(cycles_until(&mut sim, bits::RI, 2000).is_some());
+        assert_eq!(sim.read_direct(sfr_addr(&SFR::Reg(IRegs::SBUF))), b'B');
+    }
+
+    #[test]
+    fn sm2_drops_mode3_frames_with_9th_bit_cleared() {
+        let mut sim = timer1_baud(false);
+        set(&mut sim, IRegs::SCON, 0xF0);
+        sim.serial_inject9(0x41, false);
+        sim.serial_inject9(0x42, true);
+
+        let cycles = cycles_until(&mut sim, bits::RI, 5000).unwrap();
+        assert!(cycles > 2000, "{} cycles", cycles);
+        assert_eq!(sim.read_direct(sfr_addr(&SFR::Reg(IRegs::SBUF))), 0x42);
+        assert!(sim.read_bit(bits::RB8));
+    }
+
+    #[test]
+    fn frame_is_lost_while_ri_is_set() {
+        let mut sim = timer1_baud(false);
+        set(&mut sim, IRegs::SCON, 0x51);
+        sim.serial_inject(0x41);
+        sim.serial_inject(0x42);
+
+        // Long enough for the whole first frame
+        for _ in 0..1000 {
+            machine_cycle(&mut sim);
+        }
+        assert_eq!(sim.read_direct(sfr_addr(&SFR::Reg(IRegs::SBUF))), 0x00);
+        assert!(sim.read_bit(bits::RI));
+        sim.write_bit(bits::RI, false);
+        assert!(cycles_until(&mut sim, bits::RI, 2000).is_some());
+        assert_eq!(sim.read_direct(sfr_addr(&SFR::Reg(IRegs::SBUF))), 0x42);
+    }
+}
@@ -0,0 +1,387 @@
+// Timer/counter 0 and 1 of the 8051, advanced once every machine cycle (12 oscillator periods)
+//
+// TMOD    GATE1 C/T1 M1 M0 | GATE0 C/T0 M1 M0
+//  mode 0 -> 13 bit, TH counts the overflows of the lower 5 bits of TL
+//  mode 1 -> 16 bit
+//  mode 2 -> 8 bit TL reloaded from TH on overflow
+//  mode 3 -> timer 0 splits into TL0 with its own controls and TH0 controlled by TR1 setting TF1, timer 1 holds
+//
+// A timer runs while TRx is set and, with GATE set, while the INTx pin is high
+// As a counter it counts the falling edges of the Tx pin instead of the machine cycles
+//
+// Timer 2 of the 8052 is set up by T2CON    TF2 EXF2 RCLK TCLK EXEN2 TR2 C/T2 CP/RL2    and T2MOD    - - - - - - T2OE DCEN
+//  capture     -> 16 bit, a falling edge on T2EX copies TH2/TL2 into RCAP2H/RCAP2L when EXEN2 is set
+//  auto reload -> 16 bit reloaded from RCAP2H/RCAP2L on overflow, or on a T2EX falling edge with EXEN2
+//                 with DCEN set T2EX gives the direction instead, counting down to RCAP2 and then wrapping to FFFFH
+//  baud rate   -> RCLK or TCLK, counts every other oscillator period and reloads without setting TF2
+
+use crate::Sim8051::{bits, sfr_addr, IRegs, Sim8051, SFR};
+
+// Pin levels seen in the previous machine cycle, a counter counts on a 1 to 0 change
+#[derive(Debug, Clone, Copy)]
+pub struct Timers {
+    t0_pin: bool,
+    t1_pin: bool,
+    t2_pin: bool,
+    t2ex_pin: bool,
+}
+
+impl Default for Timers {
+    fn default() -> Timers {
+        // Port pins come out of reset high
+        Timers {
+            t0_pin: true,
+            t1_pin: true,
+            t2_pin: true,
+            t2ex_pin: true,
+        }
+    }
+}
+
+impl Sim8051 {
+    // One machine cycle of both timers, returns whether timer 1 overflowed as that clocks the serial port
+    pub fn tick_timers(&mut self) -> bool {
+        let tmod = self.read_direct(sfr_addr(&SFR::Reg(IRegs::TMOD)));
+        let (t0_edge, t1_edge) = self.sample_counter_pins();
+        let (mode0, mode1) = (tmod & 0x03, (tmod >> 4) & 0x03);
+
+        let counts0 = tmod & 0x04 == 0 || t0_edge;
+        if counts0 && self.timer_running(tmod & 0x08 != 0, bits::TR0, bits::INT0) {
+            let overflow = if mode0 == 3 {
+                self.count_8bit(IRegs::TL0)
+            } else {
+                self.count_timer(IRegs::TL0, IRegs::TH0, mode0)
+            };
+            if overflow {
+                self.write_bit(bits::TF0, true);
+            }
+        }
+        // TH0 of mode 3 only ever counts machine cycles and borrows TR1 and TF1 from timer 1
+        if mode0 == 3 && self.read_bit(bits::TR1) && self.count_8bit(IRegs::TH0) {
+            self.write_bit(bits::TF1, true);
+        }
+
+        // While timer 0 is in mode 3, timer 1 runs whenever it is out of mode 3 itself, but can't set TF1
+        let counts1 = tmod & 0x40 == 0 || t1_edge;
+        let running1 = if mode0 == 3 {
+            tmod & 0x80 == 0 || self.pin(bits::INT1)
+        } else {
+            self.timer_running(tmod & 0x80 != 0, bits::TR1, bits::INT1)
+        };
+        if mode1 == 3 || !counts1 || !running1 {
+            return false;
+        }
+        let overflow = self.count_timer(IRegs::TL1, IRegs::TH1, mode1);
+        if overflow && mode0 != 3 {
+            self.write_bit(bits::TF1, true);
+        }
+        overflow
+    }
+
+    fn timer_running(&self, gate: bool, run: u8, int: u8) -> bool {
+        self.read_bit(run) && (!gate || self.pin(int))
+    }
+
+    // Falling edges on T0 and T1 since the last machine cycle
+    fn sample_counter_pins(&mut self) -> (bool, bool) {
+        let (t0, t1) = (self.pin(bits::T0), self.pin(bits::T1));
+        let edges = (self.timers.t0_pin && !t0, self.timers.t1_pin && !t1);
+        self.timers.t0_pin = t0;
+        self.timers.t1_pin = t1;
+        edges
+    }
+
+    // Counts once in modes 0, 1 and 2, returns whether the timer overflowed
+    fn count_timer(&mut self, low: IRegs, high: IRegs, mode: u8) -> bool {
+        let (tl_addr, th_addr) = (sfr_addr(&SFR::Reg(low)), sfr_addr(&SFR::Reg(high)));
+        let (tl, th) = (self.read_direct(tl_addr), self.read_direct(th_addr));
+        match mode {
+            0 => {
+                // Upper 3 bits of TL are left alone
+                let count = (((th as u16) << 5) | (tl & 0x1F) as u16) + 1;
+                self.write_direct(tl_addr, (tl & 0xE0) | (count & 0x1F) as u8);
+                self.write_direct(th_addr, (count >> 5) as u8);
+                count > 0x1FFF
+            }
+            1 => {
+                let (count, overflow) = (((th as u16) << 8) | tl as u16).overflowing_add(1);
+                self.write_direct(tl_addr, count as u8);
+                self.write_direct(th_addr, (count >> 8) as u8);
+                overflow
+            }
+            _ => {
+                let (count, overflow) = tl.overflowing_add(1);
+                self.write_direct(tl_addr, if overflow { th } else { count });
+                overflow
+            }
+        }
+    }
+
+    fn count_8bit(&mut self, reg: IRegs) -> bool {
+        let addr = sfr_addr(&SFR::Reg(reg));
+        let (count, overflow) = self.read_direct(addr).overflowing_add(1);
+        self.write_direct(addr, count);
+        overflow
+    }
+
+    // One machine cycle of timer 2, returns how many times it overflowed as baud rate generator clocks the serial port
+    pub fn tick_timer2(&mut self) -> u8 {
+        let t2con = self.read_direct(sfr_addr(&SFR::Reg(IRegs::T2CON)));
+        let dcen = self.read_direct(sfr_addr(&SFR::Reg(IRegs::T2MOD))) & 0x01 != 0;
+        let (t2_edge, t2ex_edge) = self.sample_timer2_pins();
+        let baud = t2con & 0x30 != 0;
+        let capture = t2con & 0x01 != 0;
+
+        // T2EX works even while the timer is stopped, only its flag is left in baud rate generator mode
+        if t2con & 0x08 != 0 && t2ex_edge && !(dcen && !baud && !capture) {
+            if capture && !baud {
+                let count = self.timer2_count();
+                self.write_direct(sfr_addr(&SFR::Reg(IRegs::RCAP2L)), count as u8);
+                self.write_direct(sfr_addr(&SFR::Reg(IRegs::RCAP2H)), (count >> 8) as u8);
+            } else if !baud {
+                self.set_timer2_count(self.timer2_reload());
+            }
+            self.write_bit(bits::EXF2, true);
+        }
+        if t2con & 0x04 == 0 {
+            return 0;
+        }
+
+        // Timer counts fosc / 12 normally and fosc / 2 as baud rate generator, counter counts T2 edges
+        let steps = match (t2con & 0x02 != 0, baud) {
+            (true, _) => t2_edge as u8,
+            (false, true) => 6,
+            (false, false) => 1,
+        };
+        let mut overflows = 0;
+        for _ in 0..steps {
+            let count = self.timer2_count();
+            let reload = self.timer2_reload();
+            let down = dcen && !baud && !capture && !self.pin(bits::T2EX);
+            let next = if down && count == reload {
+                0xFFFF
+            } else if down {
+                count.wrapping_sub(1)
+            } else if count == 0xFFFF && (baud || !capture) {
+                reload
+            } else {
+                count.wrapping_add(1)
+            };
+            let wrapped = if down {
+                count == reload
+            } else {
+                count == 0xFFFF
+            };
+            self.set_timer2_count(next);
+            if !wrapped {
+                continue;
+            }
+            if baud {
+                overflows += 1;
+                continue;
+            }
+            self.write_bit(bits::TF2, true);
+            // EXF2 is the 17th bit of the up/down counter
+            if dcen && !capture {
+                self.write_bit(bits::EXF2, !self.read_bit(bits::EXF2));
+            }
+        }
+        overflows
+    }
+
+    // Falling edges on T2 and T2EX since the last machine cycle
+    fn sample_timer2_pins(&mut self) -> (bool, bool) {
+        let (t2, t2ex) = (self.pin(bits::T2), self.pin(bits::T2EX));
+        let edges = (self.timers.t2_pin && !t2, self.timers.t2ex_pin && !t2ex);
+        self.timers.t2_pin = t2;
+        self.timers.t2ex_pin = t2ex;
+        edges
+    }
+
+    fn timer2_count(&self) -> u16 {
+        let high = self.read_direct(sfr_addr(&SFR::Reg(IRegs::TH2))) as u16;
+        (high << 8) | self.read_direct(sfr_addr(&SFR::Reg(IRegs::TL2))) as u16
+    }
+
+    fn set_timer2_count(&mut self, count: u16) {
+        self.write_direct(sfr_addr(&SFR::Reg(IRegs::TL2)), count as u8);
+        self.write_direct(sfr_addr(&SFR::Reg(IRegs::TH2)), (count >> 8) as u8);
+    }
+
+    fn timer2_reload(&self) -> u16 {
+        let high = self.read_direct(sfr_addr(&SFR::Reg(IRegs::RCAP2H))) as u16;
+        (high << 8) | self.read_direct(sfr_addr(&SFR::Reg(IRegs::RCAP2L))) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(sim: &mut Sim8051, reg: IRegs, val: u8) {
+        sim.write_direct(sfr_addr(&SFR::Reg(reg)), val);
+    }
+
+    fn get(sim: &Sim8051, reg: IRegs) -> u8 {
+        sim.read_direct(sfr_addr(&SFR::Reg(reg)))
+    }
+
+    #[test]
+    fn mode0_rolls_over_at_13_bits() {
+        let mut sim = Sim8051::default();
+        set(&mut sim, IRegs::TH0, 0xFF);
+        set(&mut sim, IRegs::TL0, 0xFE);
+        sim.write_bit(bits::TR0, true);
+
+        sim.tick_timers();
+        assert_eq!((get(&sim, IRegs::TH0), get(&sim, IRegs::TL0)), (0xFF, 0xFF));
+        assert!(!sim.read_bit(bits::TF0));
+        // Upper 3 bits of TL0 take no part in the count
+        sim.tick_timers();
+        assert_eq!((get(&sim, IRegs::TH0), get(&sim, IRegs::TL0)), (0x00, 0xE0));
+        assert!(sim.read_bit(bits::TF0));
+    }
+
+    #[test]
+    fn mode2_reloads_from_th() {
+        let mut sim = Sim8051::default();
+        set(&mut sim, IRegs::TMOD, 0x02);
+        set(&mut sim, IRegs::TH0, 0x9C);
+        set(&mut sim, IRegs::TL0, 0xFF);
+        sim.write_bit(bits::TR0, true);
+
+        sim.tick_timers();
+        assert_eq!(get(&sim, IRegs::TL0), 0x9C);
+        assert!(sim.read_bit(bits::TF0));
+        sim.tick_timers();
+        assert_eq!(get(&sim, IRegs::TL0), 0x9D);
+    }
+
+    #[test]
+    fn mode3_th0_runs_on_tr1() {
+        let mut sim = Sim8051::default();
+        set(&mut sim, IRegs::TMOD, 0x03);
+        set(&mut sim, IRegs::TH0, 0xFF);
+        set(&mut sim, IRegs::TL0, 0x10);
+        sim.write_bit(bits::TR1, true);
+
+        sim.tick_timers();
+        assert_eq!(get(&sim, IRegs::TH0), 0x00);
+        assert!(sim.read_bit(bits::TF1));
+        // TL0 still waits for TR0
+        assert_eq!(get(&sim, IRegs::TL0), 0x10);
+        assert!(!sim.read_bit(bits::TF0));
+    }
+
+    #[test]
+    fn gate_holds_while_int0_is_low() {
+        let mut sim = Sim8051::default();
+        set(&mut sim, IRegs::TMOD, 0x09);
+        sim.write_bit(bits::TR0, true);
+
+        sim.drive_int0(false);
+        sim.tick_timers();
+        assert_eq!(get(&sim, IRegs::TL0), 0x00);
+        sim.release_pin(bits::INT0);
+        sim.tick_timers();
+        assert_eq!(get(&sim, IRegs::TL0), 0x01);
+    }
+
+    #[test]
+    fn counter_counts_falling_edges_on_t0() {
+        let mut sim = Sim8051::default();
+        set(&mut sim, IRegs::TMOD, 0x05);
+        sim.write_bit(bits::TR0, true);
+
+        let levels = [true, false, false, true, false, true, true, false];
+        let counts: Vec<u8> = levels
+            .iter()
+            .map(|level| {
+                sim.drive_pin(bits::T0, *level);
+                sim.tick_timers();
+                get(&sim, IRegs::TL0)
+            })
+            .collect();
+        assert_eq!(counts, [0, 1, 1, 1, 2, 2, 2, 3]);
+    }
+
+    fn set_timer2(sim: &mut Sim8051, count: u16, reload: u16) {
+        sim.set_timer2_count(count);
+        set(sim, IRegs::RCAP2L, reload as u8);
+        set(sim, IRegs::RCAP2H, (reload >> 8) as u8);
+    }
+
+    #[test]
+    fn timer2_reloads_from_rcap2() {
+        let mut sim = Sim8051::default();
+        set_timer2(&mut sim, 0xFFFF, 0x1234);
+        sim.write_bit(bits::TR2, true);
+
+        assert_eq!(sim.tick_timer2(), 0);
+        assert_eq!(sim.timer2_count(), 0x1234);
+        assert!(sim.read_bit(bits::TF2));
+        assert!(!sim.read_bit(bits::EXF2));
+    }
+
+    #[test]
+    fn timer2_counts_down_to_rcap2_with_dcen() {
+        let mut sim = Sim8051::default();
+        set_timer2(&mut sim, 0x1235, 0x1234);
+        set(&mut sim, IRegs::T2MOD, 0x01);
+        sim.write_bit(bits::TR2, true);
+        // T2EX low counts down
+        sim.drive_pin(bits::T2EX, false);
+
+        sim.tick_timer2();
+        assert_eq!(sim.timer2_count(), 0x1234);
+        assert!(!sim.read_bit(bits::TF2));
+        sim.tick_timer2();
+        assert_eq!(sim.timer2_count(), 0xFFFF);
+        assert!(sim.read_bit(bits::TF2));
+        assert!(sim.read_bit(bits::EXF2));
+
+        // Back up again, the overflow reloads RCAP2 and toggles EXF2 back
+        sim.release_pin(bits::T2EX);
+        sim.tick_timer2();
+        assert_eq!(sim.timer2_count(), 0x1234);
+        assert!(!sim.read_bit(bits::EXF2));
+    }
+
+    #[test]
+    fn timer2_captures_on_t2ex() {
+        let mut sim = Sim8051::default();
+        set_timer2(&mut sim, 0x5678, 0x0000);
+        sim.write_bit(bits::CP_RL2, true);
+        sim.write_bit(bits::EXEN2, true);
+        sim.write_bit(bits::TR2, true);
+
+        sim.tick_timer2();
+        assert_eq!(sim.timer2_reload(), 0x0000);
+        sim.drive_pin(bits::T2EX, false);
+        sim.tick_timer2();
+        assert_eq!(
+            (get(&sim, IRegs::RCAP2H), get(&sim, IRegs::RCAP2L)),
+            (0x56, 0x79)
+        );
+        assert_eq!(sim.timer2_count(), 0x567A);
+        assert!(sim.read_bit(bits::EXF2));
+        // Capture mode never reloads, it just rolls over to 0000H
+        sim.set_timer2_count(0xFFFF);
+        sim.tick_timer2();
+        assert_eq!(sim.timer2_count(), 0x0000);
+        assert!(sim.read_bit(bits::TF2));
+    }
+
+    #[test]
+    fn timer2_baud_counts_6_per_cycle() {
+        let mut sim = Sim8051::default();
+        set_timer2(&mut sim, 0xFFFE, 0xFFFD);
+        sim.write_bit(bits::TCLK, true);
+        sim.write_bit(bits::TR2, true);
+
+        // FFFF, FFFD with an overflow, FFFE, FFFF, FFFD with an overflow and FFFE
+        assert_eq!(sim.tick_timer2(), 2);
+        assert_eq!(sim.timer2_count(), 0xFFFE);
+        assert!(!sim.read_bit(bits::TF2));
+    }
+}
@@ -0,0 +1,84 @@
+// External data memory bus, reached with MOVX A,@DPTR / MOVX @DPTR,A and through @R0/@R1 with P2 as the high byte
+//
+// Address ranges can be handed over to devices (8255, LCD controller, latches ..), anything left unmapped is the
+// 64K of RAM in Sim8051::data_memory. A device sees addresses relative to the start of its range and the
+// one mapped last wins where ranges overlap
+
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use crate::Sim8051::Sim8051;
+
+pub trait BusDevice {
+    fn read(&mut self, offset: u16) -> u8;
+    fn write(&mut self, offset: u16, val: u8);
+    // RST of the 8051 is usually wired to the peripherals too
+    fn reset(&mut self) {}
+}
+
+// Lets the host keep a handle on a device to look at it while the program runs
+impl<D: BusDevice> BusDevice for Rc<RefCell<D>> {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.borrow_mut().read(offset)
+    }
+
+    fn write(&mut self, offset: u16, val: u8) {
+        self.borrow_mut().write(offset, val)
+    }
+
+    fn reset(&mut self) {
+        self.borrow_mut().reset()
+    }
+}
+
+#[derive(Default)]
+pub struct XdataBus {
+    devices: Vec<(RangeInclusive<u16>, Box<dyn BusDevice>)>,
+}
+
+impl XdataBus {
+    pub fn map(&mut self, range: RangeInclusive<u16>, device: Box<dyn BusDevice>) {
+        self.devices.push((range, device));
+    }
+
+    // Takes the device mapped at exactly this range off the bus
+    pub fn unmap(&mut self, range: RangeInclusive<u16>) -> Option<Box<dyn BusDevice>> {
+        let index = self.devices.iter().rposition(|(r, _)| *r == range)?;
+        Some(self.devices.remove(index).1)
+    }
+
+    pub fn reset(&mut self) {
+        for (_, device) in self.devices.iter_mut() {
+            device.reset();
+        }
+    }
+
+    fn device(&mut self, addr: u16) -> Option<(u16, &mut Box<dyn BusDevice>)> {
+        self.devices
+            .iter_mut()
+            .rev()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|(range, device)| (addr - range.start(), device))
+    }
+}
+
+impl Sim8051 {
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: impl BusDevice + 'static) {
+        self.xdata.map(range, Box::new(device));
+    }
+
+    pub fn read_xdata(&mut self, addr: u16) -> u8 {
+        match self.xdata.device(addr) {
+            Some((offset, device)) => device.read(offset),
+            None => self.data_memory[addr as usize],
+        }
+    }
+
+    pub fn write_xdata(&mut self, addr: u16, val: u8) {
+        match self.xdata.device(addr) {
+            Some((offset, device)) => device.write(offset, val),
+            None => self.data_memory[addr as usize] = val,
+        }
+    }
+}
@@ -2,6 +2,6 @@
 	mov R0, #45H
 	add A,  R0
 	da  A
-	setb psw.x
+	setb PSW.3
 	xch A,  R0
 	end  